@@ -0,0 +1,719 @@
+//! What changed in a timetable between two KEATS snapshots, for students to read.
+//!
+//! Sessions are matched by id first, so changes to their room, campus or staff are spotted.
+//! Sessions left over are matched by module code and raw groups, nearest in time first, as a session that has moved gets a new id.
+//! Anything still unmatched was added or cancelled.
+//! KEATS events that can't be read are reported separately, rather than their sessions being cancelled.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
+use chrono_tz::Europe::London;
+
+use crate::keats::groups_parser::{format_groups, parse_group_range, GroupUniverse};
+use crate::{
+    disambiguate_ids, filter, keats, Action, Diagnostic, Event, EventInner, GroupAssignment,
+};
+
+/// A session moved any further is reported as cancelled and added, as it is more likely another session.
+fn max_move() -> Duration {
+    Duration::weeks(1)
+}
+
+/// Which sessions a changelog covers, chosen as for a calendar update.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ChangeFilter {
+    pub groups: Vec<GroupAssignment>,
+    #[serde(default)]
+    pub universe: GroupUniverse,
+    #[serde(default)]
+    pub filter: filter::Filter,
+    /// Only changes to sessions ending after this time, before or after the change.
+    #[serde(default)]
+    pub time_min: Option<DateTime<FixedOffset>>,
+    /// Only changes to sessions starting before this time, before or after the change.
+    #[serde(default)]
+    pub time_max: Option<DateTime<FixedOffset>>,
+}
+
+impl ChangeFilter {
+    fn in_window(&self, event: &Event) -> bool {
+        self.time_min.is_none_or(|min| event.is_after(&min))
+            && self.time_max.is_none_or(|max| event.is_before(&max))
+    }
+
+    /// Whether an invalid KEATS event may have been for one of the groups, going by its groups alone.
+    fn may_select(&self, event: &keats::Event) -> bool {
+        let groups = parse_group_range(event.groups.as_deref().unwrap_or_default(), &self.universe);
+        self.groups
+            .iter()
+            .any(|assignment| groups.contains(&assignment.group))
+    }
+
+    /// The selected sessions of a snapshot by id, and the invalid KEATS events that may have been selected.
+    fn select(&self, snapshot: &[keats::Event]) -> (BTreeMap<String, Event>, Vec<Diagnostic>) {
+        let mut events = vec![];
+        let mut unreadable = vec![];
+        for keats_event in snapshot {
+            match Event::from_keats(keats_event.clone(), &self.universe) {
+                Ok((event, _)) => {
+                    if self.filter.matches(&event, &self.groups) {
+                        events.push(event);
+                    }
+                }
+                Err(error) => {
+                    if self.may_select(keats_event) {
+                        unreadable.push(Diagnostic {
+                            event: keats_event.clone(),
+                            reason: error.to_string(),
+                            action: Action::Skipped,
+                        });
+                    }
+                }
+            }
+        }
+        let events = disambiguate_ids(events)
+            .into_iter()
+            .map(|event| (event.id.clone(), event))
+            .collect();
+        (events, unreadable)
+    }
+}
+
+/// How a timetable changed between two snapshots.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changelog {
+    /// Changes to the selected sessions, in time order.
+    pub changes: Vec<Change>,
+    /// KEATS events in the new snapshot that could not be read, but may be for the selected groups.
+    ///
+    /// Sessions with the same module code and raw groups, on the same date if it can be read,
+    /// are not reported as cancelled, as they may still take place.
+    pub unreadable: Vec<Diagnostic>,
+}
+
+/// A change to a session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A new session.
+    Added(EventInner),
+    /// A session that no longer takes place.
+    Cancelled(EventInner),
+    /// A session with a new start or end time.
+    Moved { old: EventInner, new: EventInner },
+    /// A session with a new room or campus.
+    Relocated { old: EventInner, new: EventInner },
+    /// A session with new staff.
+    Restaffed { old: EventInner, new: EventInner },
+}
+
+fn local_date(datetime: &DateTime<FixedOffset>) -> NaiveDate {
+    datetime.with_timezone(&London).date().naive_local()
+}
+
+/// The date of a KEATS event, if it can be read even though the event can't.
+fn keats_date(event: &keats::Event) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&event.date, "%Y-%m-%dT%H:%M:%S").ok()
+}
+
+fn local_time(datetime: &DateTime<FixedOffset>) -> String {
+    datetime.with_timezone(&London).format("%H:%M").to_string()
+}
+
+/// When a session is, like `Mon 9 Sep 09:00-12:30`.
+fn when(event: &EventInner) -> String {
+    format!(
+        "{}-{}",
+        event.start.with_timezone(&London).format("%a %-d %b %H:%M"),
+        local_time(&event.end)
+    )
+}
+
+/// What a session is, like `Anatomy practical (5MBBS201) for 253-256`.
+fn what(event: &EventInner) -> String {
+    let mut what = match &event.title {
+        Some(title) => format!("{} ({})", title, event.code),
+        None => event.code.clone(),
+    };
+    if event.groups_raw.is_some() {
+        what.push_str(&format!(" for {}", format_groups(&event.groups)));
+    }
+    what
+}
+
+fn location(event: &EventInner) -> String {
+    let parts: Vec<&str> = vec![event.room.as_deref(), event.campus.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    if parts.is_empty() {
+        "no room".to_owned()
+    } else {
+        parts.join(", ")
+    }
+}
+
+fn staff(event: &EventInner) -> &str {
+    event.staff.as_deref().unwrap_or("no staff")
+}
+
+impl Change {
+    /// The kind of change, like `Moved`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Change::Added(_) => "Added",
+            Change::Cancelled(_) => "Cancelled",
+            Change::Moved { .. } => "Moved",
+            Change::Relocated { .. } => "Relocated",
+            Change::Restaffed { .. } => "Restaffed",
+        }
+    }
+
+    /// The session as it is now, or as it was if cancelled.
+    pub fn session(&self) -> &EventInner {
+        match self {
+            Change::Added(event) | Change::Cancelled(event) => event,
+            Change::Moved { new, .. }
+            | Change::Relocated { new, .. }
+            | Change::Restaffed { new, .. } => new,
+        }
+    }
+
+    /// A sentence describing the change, without its kind.
+    pub fn description(&self) -> String {
+        match self {
+            Change::Added(event) | Change::Cancelled(event) => {
+                format!("{} {} in {}", when(event), what(event), location(event))
+            }
+            Change::Moved { old, new } => {
+                format!("{} from {} to {}", what(new), when(old), when(new))
+            }
+            Change::Relocated { old, new } => format!(
+                "{} {} from {} to {}",
+                when(new),
+                what(new),
+                location(old),
+                location(new)
+            ),
+            Change::Restaffed { old, new } => format!(
+                "{} {} from {} to {}",
+                when(new),
+                what(new),
+                staff(old),
+                staff(new)
+            ),
+        }
+    }
+}
+
+/// The changes between the same session in two snapshots.
+fn compare(old: &EventInner, new: &EventInner) -> Vec<Change> {
+    let mut changes = vec![];
+    if old.start != new.start || old.end != new.end {
+        changes.push(Change::Moved {
+            old: old.clone(),
+            new: new.clone(),
+        });
+    }
+    if old.room != new.room || old.campus != new.campus {
+        changes.push(Change::Relocated {
+            old: old.clone(),
+            new: new.clone(),
+        });
+    }
+    if old.staff != new.staff {
+        changes.push(Change::Restaffed {
+            old: old.clone(),
+            new: new.clone(),
+        });
+    }
+    changes
+}
+
+/// Report how the sessions selected by `filter` changed from the `old` snapshot to the `new` one.
+pub fn diff_snapshots(
+    old: &[keats::Event],
+    new: &[keats::Event],
+    filter: &ChangeFilter,
+) -> Changelog {
+    let (mut old, _) = filter.select(old);
+    let (mut new, unreadable) = filter.select(new);
+    let mut pairs = vec![];
+
+    // The same session, which may have been relocated or restaffed
+    let ids: Vec<String> = old
+        .keys()
+        .filter(|id| new.contains_key(*id))
+        .cloned()
+        .collect();
+    for id in ids {
+        pairs.push((old.remove(&id).unwrap(), new.remove(&id).unwrap()));
+    }
+
+    // A moved session, nearest in time to where it was, and not too far away.
+    // Only sessions of the same module on nearby days are compared, found by module code and date.
+    let max_move = max_move();
+    let mut old_left: Vec<Option<Event>> = old.into_values().map(Some).collect();
+    let mut new_left: Vec<Option<Event>> = new.into_values().map(Some).collect();
+    let mut candidates = {
+        let mut new_by_day: HashMap<(&str, NaiveDate), Vec<(usize, &Event)>> = HashMap::new();
+        for (j, n) in new_left.iter().flatten().enumerate() {
+            new_by_day
+                .entry((&n.inner.code, local_date(&n.inner.start)))
+                .or_default()
+                .push((j, n));
+        }
+        let mut candidates = vec![];
+        for (i, o) in old_left.iter().flatten().enumerate() {
+            let date = local_date(&o.inner.start);
+            for days in -max_move.num_days()..=max_move.num_days() {
+                let key = (o.inner.code.as_str(), date + Duration::days(days));
+                for (j, n) in new_by_day.get(&key).into_iter().flatten() {
+                    let distance = (n.inner.start - o.inner.start).num_seconds().abs();
+                    if o.inner.groups_raw == n.inner.groups_raw
+                        && distance <= max_move.num_seconds()
+                    {
+                        candidates.push((distance, i, *j));
+                    }
+                }
+            }
+        }
+        candidates
+    };
+    candidates.sort();
+    for (_, i, j) in candidates {
+        if old_left[i].is_some() && new_left[j].is_some() {
+            pairs.push((old_left[i].take().unwrap(), new_left[j].take().unwrap()));
+        }
+    }
+
+    let mut changes = vec![];
+    for (old_event, new_event) in pairs {
+        if filter.in_window(&old_event) || filter.in_window(&new_event) {
+            changes.extend(compare(&old_event.inner, &new_event.inner));
+        }
+    }
+    for event in old_left.into_iter().flatten() {
+        // An unreadable event with a readable date may only be the session on that date,
+        // otherwise it may be any of the module's sessions for the same groups
+        let maybe_unreadable = unreadable.iter().any(|diagnostic| {
+            diagnostic.event.code == event.inner.code
+                && diagnostic.event.groups == event.inner.groups_raw
+                && keats_date(&diagnostic.event)
+                    .is_none_or(|date| date == local_date(&event.inner.start))
+        });
+        if filter.in_window(&event) && !maybe_unreadable {
+            changes.push(Change::Cancelled(event.inner));
+        }
+    }
+    for event in new_left.into_iter().flatten() {
+        if filter.in_window(&event) {
+            changes.push(Change::Added(event.inner));
+        }
+    }
+    changes.sort_by(|a, b| {
+        (a.session().start, &a.session().code, a.kind()).cmp(&(
+            b.session().start,
+            &b.session().code,
+            b.kind(),
+        ))
+    });
+    Changelog {
+        changes,
+        unreadable,
+    }
+}
+
+/// An unreadable KEATS event, like `5MBBS201 on 2019-09-13: Could not parse field 'ST' with value '25:00'`.
+fn describe_unreadable(diagnostic: &Diagnostic) -> String {
+    let date = diagnostic.event.date.split('T').next().unwrap_or_default();
+    format!(
+        "{} on {}: {}",
+        diagnostic.event.code, date, diagnostic.reason
+    )
+}
+
+/// Render changes as plain text, one per line, followed by any unreadable events.
+pub fn render_text(changelog: &Changelog) -> String {
+    if changelog.changes.is_empty() && changelog.unreadable.is_empty() {
+        return "No changes\n".to_owned();
+    }
+    let changes = changelog
+        .changes
+        .iter()
+        .map(|change| format!("{}: {}\n", change.kind(), change.description()));
+    let unreadable = changelog
+        .unreadable
+        .iter()
+        .map(|diagnostic| format!("Unreadable: {}\n", describe_unreadable(diagnostic)));
+    changes.chain(unreadable).collect()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Render changes as an HTML list, with each item classed by its kind of change, followed by any unreadable events.
+pub fn render_html(changelog: &Changelog) -> String {
+    if changelog.changes.is_empty() && changelog.unreadable.is_empty() {
+        return "<p class=\"changes\">No changes</p>\n".to_owned();
+    }
+    let items = changelog
+        .changes
+        .iter()
+        .map(|change| (change.kind(), change.description()))
+        .chain(
+            changelog
+                .unreadable
+                .iter()
+                .map(|diagnostic| ("Unreadable", describe_unreadable(diagnostic))),
+        );
+    let mut html = "<ul class=\"changes\">\n".to_owned();
+    for (kind, description) in items {
+        html.push_str(&format!(
+            "  <li class=\"{}\"><strong>{}</strong> {}</li>\n",
+            kind.to_lowercase(),
+            kind,
+            escape_html(&description)
+        ));
+    }
+    html.push_str("</ul>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keats::groups_parser::Group;
+
+    fn keats_event(
+        date: &str,
+        start: &str,
+        end: &str,
+        room: &str,
+        staff: Option<&str>,
+    ) -> keats::Event {
+        keats::Event {
+            date: format!("{}T00:00:00", date),
+            start_time: start.to_owned(),
+            end_time: end.to_owned(),
+            code: "5MBBS201".to_owned(),
+            groups: Some("253-256".to_owned()),
+            title: Some("Anatomy practical".to_owned()),
+            type_: Some("Practical".to_owned()),
+            staff: staff.map(str::to_owned),
+            room: Some(room.to_owned()),
+            campus: Some("Guy's".to_owned()),
+        }
+    }
+
+    fn lecture(date: &str) -> keats::Event {
+        keats::Event {
+            code: "5MBBS202".to_owned(),
+            groups: None,
+            title: Some("Renal physiology".to_owned()),
+            type_: Some("Lecture".to_owned()),
+            ..keats_event(date, "14:00", "15:00", "Greenwood Theatre", None)
+        }
+    }
+
+    fn filter() -> ChangeFilter {
+        ChangeFilter {
+            groups: vec![Group::from(253).into()],
+            universe: Default::default(),
+            filter: Default::default(),
+            time_min: None,
+            time_max: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let old = vec![
+            keats_event(
+                "2019-09-09",
+                "09:00",
+                "12:30",
+                "Dissection Room",
+                Some("Dr A"),
+            ),
+            lecture("2019-09-10"),
+        ];
+        assert_eq!(diff_snapshots(&old, &old, &filter()), Changelog::default());
+        assert_eq!(render_text(&Changelog::default()), "No changes\n");
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let old = vec![
+            // Relocated and restaffed
+            keats_event(
+                "2019-09-09",
+                "09:00",
+                "12:30",
+                "Dissection Room",
+                Some("Dr A"),
+            ),
+            // Moved to another day
+            keats_event(
+                "2019-09-16",
+                "09:00",
+                "12:30",
+                "Dissection Room",
+                Some("Dr A"),
+            ),
+            // Cancelled
+            lecture("2019-09-10"),
+            // For other groups
+            keats::Event {
+                groups: Some("201-204".to_owned()),
+                ..lecture("2019-09-11")
+            },
+        ];
+        let new = vec![
+            keats_event("2019-09-09", "09:00", "12:30", "Dissection Room 2", None),
+            keats_event(
+                "2019-09-17",
+                "10:00",
+                "13:00",
+                "Dissection Room",
+                Some("Dr A"),
+            ),
+            // Added
+            keats::Event {
+                code: "5MBBS203".to_owned(),
+                title: Some("Cardiac physiology".to_owned()),
+                ..lecture("2019-09-12")
+            },
+            // Invalid, so reported separately
+            keats_event("2019-09-13", "25:00", "26:00", "Dissection Room", None),
+            // Invalid, but for other groups
+            keats::Event {
+                groups: Some("201-204".to_owned()),
+                ..keats_event("2019-09-13", "25:00", "26:00", "Dissection Room", None)
+            },
+        ];
+        let changelog = diff_snapshots(&old, &new, &filter());
+        assert_eq!(
+            changelog
+                .changes
+                .iter()
+                .map(Change::kind)
+                .collect::<Vec<_>>(),
+            vec!["Relocated", "Restaffed", "Cancelled", "Added", "Moved"]
+        );
+        assert_eq!(changelog.unreadable.len(), 1);
+        assert_eq!(
+            render_text(&changelog),
+            "Relocated: Mon 9 Sep 09:00-12:30 Anatomy practical (5MBBS201) for 253-256 from Dissection Room, Guy's to Dissection Room 2, Guy's\n\
+             Restaffed: Mon 9 Sep 09:00-12:30 Anatomy practical (5MBBS201) for 253-256 from Dr A to no staff\n\
+             Cancelled: Tue 10 Sep 14:00-15:00 Renal physiology (5MBBS202) in Greenwood Theatre, Guy's\n\
+             Added: Thu 12 Sep 14:00-15:00 Cardiac physiology (5MBBS203) in Greenwood Theatre, Guy's\n\
+             Moved: Anatomy practical (5MBBS201) for 253-256 from Mon 16 Sep 09:00-12:30 to Tue 17 Sep 10:00-13:00\n\
+             Unreadable: 5MBBS201 on 2019-09-13: Could not parse field 'ST' with value '25:00'\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_unreadable() {
+        let old = vec![
+            keats_event("2019-09-09", "09:00", "12:30", "Dissection Room", None),
+            keats_event("2019-09-16", "09:00", "12:30", "Dissection Room", None),
+            lecture("2019-09-10"),
+        ];
+        // The practical on its date can't be read, so may not be cancelled, unlike the lecture
+        // or the practical a week later
+        let new = vec![keats_event(
+            "2019-09-09",
+            "09:00",
+            "spam",
+            "Dissection Room",
+            None,
+        )];
+        let changelog = diff_snapshots(&old, &new, &filter());
+        let cancelled: Vec<(&str, String)> = changelog
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::Cancelled(event) => {
+                    (event.code.as_str(), local_date(&event.start).to_string())
+                }
+                other => panic!("Expected only cancellations, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            cancelled,
+            vec![
+                ("5MBBS202", "2019-09-10".to_owned()),
+                ("5MBBS201", "2019-09-16".to_owned())
+            ]
+        );
+        assert_eq!(changelog.unreadable.len(), 1);
+        assert_eq!(changelog.unreadable[0].event, new[0]);
+        assert_eq!(changelog.unreadable[0].action, Action::Skipped);
+
+        // Without a readable date, it may be any of the module's practicals
+        let undated = vec![keats::Event {
+            date: "spam".to_owned(),
+            ..new[0].clone()
+        }];
+        let changelog = diff_snapshots(&old, &undated, &filter());
+        match changelog.changes.as_slice() {
+            [Change::Cancelled(event)] => assert_eq!(event.code, "5MBBS202"),
+            other => panic!("Expected one cancellation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diff_moved_too_far() {
+        // A session two weeks later is more likely another one
+        let old = vec![keats_event(
+            "2019-09-09",
+            "09:00",
+            "12:30",
+            "Dissection Room",
+            None,
+        )];
+        let new = vec![keats_event(
+            "2019-09-23",
+            "09:00",
+            "12:30",
+            "Dissection Room",
+            None,
+        )];
+        assert_eq!(
+            diff_snapshots(&old, &new, &filter())
+                .changes
+                .iter()
+                .map(Change::kind)
+                .collect::<Vec<_>>(),
+            vec!["Cancelled", "Added"]
+        );
+
+        // But a week later is still a move
+        let new = vec![keats_event(
+            "2019-09-16",
+            "09:00",
+            "12:30",
+            "Dissection Room",
+            None,
+        )];
+        assert_eq!(
+            diff_snapshots(&old, &new, &filter())
+                .changes
+                .iter()
+                .map(Change::kind)
+                .collect::<Vec<_>>(),
+            vec!["Moved"]
+        );
+    }
+
+    #[test]
+    fn test_diff_moved_nearest() {
+        // Weekly sessions, with one moved by a day
+        let old = vec![
+            keats_event("2019-09-09", "09:00", "12:30", "Dissection Room", None),
+            keats_event("2019-09-16", "09:00", "12:30", "Dissection Room", None),
+        ];
+        let new = vec![
+            keats_event("2019-09-09", "09:00", "12:30", "Dissection Room", None),
+            keats_event("2019-09-17", "09:00", "12:30", "Dissection Room", None),
+        ];
+        match diff_snapshots(&old, &new, &filter()).changes.as_slice() {
+            [Change::Moved { old, new }] => {
+                assert_eq!(old.start.to_rfc3339(), "2019-09-16T08:00:00+00:00");
+                assert_eq!(new.start.to_rfc3339(), "2019-09-17T08:00:00+00:00");
+            }
+            other => panic!("Expected one move, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diff_window() {
+        let old = vec![
+            keats_event("2019-09-09", "09:00", "12:30", "Dissection Room", None),
+            keats_event("2019-10-07", "09:00", "12:30", "Dissection Room", None),
+        ];
+        // Both sessions move a week later
+        let new = vec![
+            keats_event("2019-09-16", "09:00", "12:30", "Dissection Room", None),
+            keats_event("2019-10-14", "09:00", "12:30", "Dissection Room", None),
+        ];
+        let window = ChangeFilter {
+            time_min: Some(DateTime::parse_from_rfc3339("2019-09-10T00:00:00+01:00").unwrap()),
+            time_max: Some(DateTime::parse_from_rfc3339("2019-10-01T00:00:00+01:00").unwrap()),
+            ..filter()
+        };
+        // A move into the window counts, but not one outside it
+        let changes = diff_snapshots(&old, &new, &window).changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].description(),
+            "Anatomy practical (5MBBS201) for 253-256 from Mon 9 Sep 09:00-12:30 to Mon 16 Sep 09:00-12:30"
+        );
+    }
+
+    #[test]
+    fn test_render_html() {
+        let old = vec![keats_event(
+            "2019-09-09",
+            "09:00",
+            "12:30",
+            "Room <1>",
+            None,
+        )];
+        let new = vec![keats_event(
+            "2019-09-09",
+            "09:00",
+            "12:30",
+            "Room & Co",
+            None,
+        )];
+        let changes = diff_snapshots(&old, &new, &filter());
+        assert_eq!(
+            render_html(&changes),
+            "<ul class=\"changes\">\n  \
+             <li class=\"relocated\"><strong>Relocated</strong> Mon 9 Sep 09:00-12:30 Anatomy practical (5MBBS201) for 253-256 from Room &lt;1&gt;, Guy&#39;s to Room &amp; Co, Guy&#39;s</li>\n\
+             </ul>\n"
+        );
+        assert_eq!(
+            render_html(&Changelog::default()),
+            "<p class=\"changes\">No changes</p>\n"
+        );
+
+        let new = vec![keats_event(
+            "2019-09-09",
+            "09:00",
+            "spam",
+            "Room & Co",
+            None,
+        )];
+        assert_eq!(
+            render_html(&diff_snapshots(&old, &new, &filter())),
+            "<ul class=\"changes\">\n  \
+             <li class=\"unreadable\"><strong>Unreadable</strong> 5MBBS201 on 2019-09-09: Could not parse field &#39;ET&#39; with value &#39;spam&#39;</li>\n\
+             </ul>\n"
+        );
+    }
+
+    #[test]
+    fn test_change_filter_from_json() {
+        let filter: ChangeFilter = serde_json::from_str(
+            r#"{"groups": [253], "filter": {"type": "Lecture"}, "time_min": "2019-09-10T00:00:00+01:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(filter.filter, filter::Filter::Type("Lecture".to_owned()));
+        assert_eq!(filter.time_max, None);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+
+/// Everything that can go wrong when calculating a calendar update.
+#[derive(Debug)]
+pub enum Error {
+    /// A KEATS date or time field could not be parsed.
+    Parse {
+        /// The KEATS key of the field, e.g. `ST`.
+        field: &'static str,
+        value: String,
+        source: chrono::ParseError,
+    },
+    /// A local time does not exist in London, e.g. it falls in the gap when clocks go forward.
+    Timezone(NaiveDateTime),
+    /// An event finishes before it has started.
+    EndBeforeStart {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+    },
+    /// An existing Google Event was listed more than once, so the list cannot be trusted.
+    DuplicateExisting(String),
+    /// A request could not be deserialized, or a response serialized.
+    Serde(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse { field, value, .. } => {
+                write!(
+                    f,
+                    "Could not parse field '{}' with value '{}'",
+                    field, value
+                )
+            }
+            Error::Timezone(datetime) => {
+                write!(
+                    f,
+                    "Local time '{}' does not exist in Europe/London",
+                    datetime
+                )
+            }
+            Error::EndBeforeStart { start, end } => write!(
+                f,
+                "End time '{}' is before start time '{}'",
+                end.to_rfc3339(),
+                start.to_rfc3339()
+            ),
+            Error::DuplicateExisting(id) => write!(
+                f,
+                "Existing event '{}' was listed more than once, so the list is inconsistent",
+                id
+            ),
+            Error::Serde(error) => write!(f, "Serialization error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse { source, .. } => Some(source),
+            Error::Timezone(_) | Error::EndBeforeStart { .. } | Error::DuplicateExisting(_) => None,
+            Error::Serde(error) => Some(error),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Serde(error)
+    }
+}
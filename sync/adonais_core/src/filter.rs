@@ -0,0 +1,145 @@
+use crate::{Event, GroupAssignment};
+
+/// User preferences deciding which events belong in their calendar.
+///
+/// Text comparisons ignore case. In JSON, rules look like
+/// `{"all": ["assigned", {"not": {"type": "Self-directed learning"}}]}`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Events for any group the user is assigned to on the day.
+    Assigned,
+    /// Events of this type, e.g. `Lecture`.
+    Type(String),
+    /// Events for this module code.
+    Code(String),
+    /// Events on this campus.
+    Campus(String),
+    /// Events with this text anywhere in their title, type or code.
+    Keyword(String),
+    /// Events not matching a rule, to exclude them.
+    Not(Box<Filter>),
+    /// Events matching every rule. With no rules, every event matches.
+    All(Vec<Filter>),
+    /// Events matching any rule. With no rules, no event matches.
+    Any(Vec<Filter>),
+}
+
+impl Default for Filter {
+    /// Only events for the user's groups.
+    fn default() -> Self {
+        Filter::Assigned
+    }
+}
+
+fn equals_ignore_case(field: &Option<String>, value: &str) -> bool {
+    field
+        .as_ref()
+        .is_some_and(|field| field.eq_ignore_ascii_case(value))
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Event, assignments: &[GroupAssignment]) -> bool {
+        let inner = &event.inner;
+        match self {
+            Filter::Assigned => event.is_assigned(assignments),
+            Filter::Type(type_) => equals_ignore_case(&inner.type_, type_),
+            Filter::Code(code) => inner.code.eq_ignore_ascii_case(code),
+            Filter::Campus(campus) => equals_ignore_case(&inner.campus, campus),
+            Filter::Keyword(keyword) => {
+                let keyword = keyword.to_lowercase();
+                vec![
+                    inner.title.as_ref(),
+                    inner.type_.as_ref(),
+                    Some(&inner.code),
+                ]
+                .into_iter()
+                .flatten()
+                .any(|field| field.to_lowercase().contains(&keyword))
+            }
+            Filter::Not(filter) => !filter.matches(event, assignments),
+            Filter::All(filters) => filters
+                .iter()
+                .all(|filter| filter.matches(event, assignments)),
+            Filter::Any(filters) => filters
+                .iter()
+                .any(|filter| filter.matches(event, assignments)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::keats;
+    use crate::keats::groups_parser::Group;
+
+    fn event(type_: &str, code: &str, title: &str) -> Event {
+        Event::try_from(keats::Event {
+            date: "2017-11-12T00:00:00".to_owned(),
+            start_time: "14:03".to_owned(),
+            end_time: "15:00".to_owned(),
+            code: code.to_owned(),
+            groups: Some("253-256".to_owned()),
+            title: Some(title.to_owned()),
+            type_: Some(type_.to_owned()),
+            staff: None,
+            room: None,
+            campus: Some("Guy's".to_owned()),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_filter_from_json() {
+        let filter: Filter = serde_json::from_str(
+            r#"{"all": [
+                {"any": ["assigned", {"keyword": "optional"}]},
+                {"not": {"type": "Self-directed learning"}},
+                {"not": {"code": "MBBS0001"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            filter,
+            Filter::All(vec![
+                Filter::Any(vec![
+                    Filter::Assigned,
+                    Filter::Keyword("optional".to_owned())
+                ]),
+                Filter::Not(Box::new(Filter::Type("Self-directed learning".to_owned()))),
+                Filter::Not(Box::new(Filter::Code("MBBS0001".to_owned()))),
+            ])
+        );
+
+        let assignments = vec![Group::from(253).into()];
+        let lecture = event("Lecture", "MBBS0002", "Pharmacology");
+        let self_directed = event("Self-Directed Learning", "MBBS0002", "Pharmacology");
+        let excluded_module = event("Lecture", "mbbs0001", "Pharmacology");
+        assert!(filter.matches(&lecture, &assignments));
+        assert!(!filter.matches(&self_directed, &assignments));
+        assert!(!filter.matches(&excluded_module, &assignments));
+
+        // Optional sessions are included for other groups by keyword
+        let other_groups = vec![Group::from(201).into()];
+        let optional = event("Lecture", "MBBS0002", "Optional revision");
+        assert!(!filter.matches(&lecture, &other_groups));
+        assert!(filter.matches(&optional, &other_groups));
+    }
+
+    #[test]
+    fn test_filter_fields() {
+        let lecture = event("Lecture", "MBBS0002", "Pharmacology");
+        let assignments = vec![];
+        assert!(Filter::Campus("guy's".to_owned()).matches(&lecture, &assignments));
+        assert!(!Filter::Campus("Denmark Hill".to_owned()).matches(&lecture, &assignments));
+        assert!(Filter::Keyword("MACOL".to_owned()).matches(&lecture, &assignments));
+        assert!(Filter::Keyword("0002".to_owned()).matches(&lecture, &assignments));
+        assert!(!Filter::Keyword("Guy".to_owned()).matches(&lecture, &assignments));
+        assert!(Filter::All(vec![]).matches(&lecture, &assignments));
+        assert!(!Filter::Any(vec![]).matches(&lecture, &assignments));
+        assert!(!Filter::default().matches(&lecture, &assignments));
+    }
+}
@@ -1,11 +1,141 @@
-#[derive(Clone, Debug, PartialEq, Serialize)]
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+
+/// Key in `extendedProperties.private` holding the content fingerprint of the source event.
+pub const FINGERPRINT_KEY: &str = "fingerprint";
+/// Key in `extendedProperties.private` holding a hash of the content as generated, to spot edits by hand.
+pub const CONTENT_KEY: &str = "content";
+/// Key in `extendedProperties.private` holding the `FORMAT_VERSION` the event was generated with.
+pub const VERSION_KEY: &str = "adonais_version";
+/// Key in `extendedProperties.private` holding the KEATS module code of the source event.
+pub const CODE_KEY: &str = "code";
+/// Key in `extendedProperties.private` holding the groups of the source event, as written in KEATS.
+pub const GROUPS_KEY: &str = "groups";
+/// Key in `extendedProperties.private` holding a hash of the style applied by the user's rules, even if none applied.
+pub const STYLE_KEY: &str = "style";
+
+/// The format of events generated now.
+///
+/// Bump this whenever generated events change, so that events generated before are regenerated.
+/// Before this, the crate version was recorded, which was `0.1.0` throughout.
+pub const FORMAT_VERSION: &str = "3";
+
+/// Hash the content of an event that a user might edit.
+fn content_hash(
+    summary: &str,
+    description: &str,
+    location: &str,
+    start: Option<i64>,
+    end: Option<i64>,
+) -> String {
+    crate::hash_id(&(summary, description, location, start, end))
+}
+
+/// The start or end of an event, [as specified in the Calendar API](https://developers.google.com/calendar/v3/reference/events#start)
+///
+/// Timed events have a `datetime`, and all-day events a `date`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
 pub struct Time {
-    #[serde(rename(serialize = "dateTime"))]
-    pub datetime: String,
+    /// An RFC3339 timestamp.
+    #[serde(
+        rename(serialize = "dateTime"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub datetime: Option<String>,
+    /// A date in the format `yyyy-mm-dd`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// An IANA time zone name, e.g. `Europe/London`.
+    #[serde(
+        rename(serialize = "timeZone"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub time_zone: Option<String>,
 }
 
-/// A Google Event resource for insertion, [as specified in the Calendar API](https://developers.google.com/calendar/v3/reference/events/insert)
+impl Time {
+    pub fn from_datetime(datetime: &DateTime<FixedOffset>) -> Self {
+        Time {
+            datetime: Some(datetime.to_rfc3339()),
+            ..Default::default()
+        }
+    }
+
+    /// For all-day events. The end date is exclusive.
+    pub fn from_date(date: &NaiveDate) -> Self {
+        Time {
+            date: Some(date.format("%Y-%m-%d").to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// How a reminder is delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReminderMethod {
+    Email,
+    Popup,
+}
+
+/// A reminder before an event starts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reminder {
+    pub method: ReminderMethod,
+    pub minutes: u32,
+}
+
+/// Reminders for an event, [as specified in the Calendar API](https://developers.google.com/calendar/v3/reference/events#reminders)
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Reminders {
+    /// Whether the calendar's default reminders apply, which must be false to use `overrides`.
+    #[serde(rename(serialize = "useDefault"))]
+    pub use_default: bool,
+    /// Always sent, even if empty, as a patch merges nested objects and would keep old overrides.
+    pub overrides: Vec<Reminder>,
+}
+
+/// Whether an event blocks time in the calendar.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transparency {
+    /// Busy.
+    Opaque,
+    /// Free.
+    Transparent,
+}
+
+/// Who can see the details of an event.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Default,
+    Public,
+    Private,
+    Confidential,
+}
+
+/// Where an event came from, shown as a link.
 #[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Source {
+    pub url: String,
+    pub title: String,
+}
+
+/// Custom properties stored against a Google Event, [as specified in the Calendar API](https://developers.google.com/calendar/v3/reference/events#extendedProperties)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedProperties {
+    /// Properties only visible to this calendar.
+    #[serde(default)]
+    pub private: HashMap<String, String>,
+}
+
+/// A Google Event resource for insertion, [as specified in the Calendar API](https://developers.google.com/calendar/v3/reference/events/insert)
+///
+/// Optional fields are left out when unset, so the calendar's defaults apply.
+/// Patches keep fields that are left out, so generated events always set the fields styles may change.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
 pub struct Event {
     pub id: String,
     pub start: Time,
@@ -13,4 +143,341 @@ pub struct Event {
     pub summary: String,
     pub description: String,
     pub location: String,
+    /// One of the [event colours](https://developers.google.com/calendar/v3/reference/colors), e.g. `"9"` for blue.
+    /// Always sent, as `null` for the calendar's colour, so that a patch clears any colour set before.
+    #[serde(rename(serialize = "colorId"))]
+    pub color_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reminders: Option<Reminders>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transparency: Option<Transparency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    #[serde(rename(serialize = "extendedProperties"))]
+    pub extended_properties: ExtendedProperties,
+}
+
+impl Event {
+    /// Hash of the content as generated, to store alongside it.
+    pub fn content_hash(&self) -> String {
+        let timestamp = |time: &Time| {
+            time.datetime
+                .as_ref()
+                .and_then(|datetime| DateTime::parse_from_rfc3339(datetime).ok())
+                .map(|datetime| datetime.timestamp())
+        };
+        content_hash(
+            &self.summary,
+            &self.description,
+            &self.location,
+            timestamp(&self.start),
+            timestamp(&self.end),
+        )
+    }
+}
+
+/// The start or end of an existing event, which is either a time or a whole day.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ExistingTime {
+    #[serde(rename(deserialize = "dateTime"), default)]
+    pub datetime: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub date: Option<NaiveDate>,
+}
+
+/// A Google Event resource already in the calendar, [as returned by the Calendar API](https://developers.google.com/calendar/v3/reference/events/list)
+///
+/// Only the fields needed to calculate an update are deserialized.
+/// Google leaves out empty text fields, so they default to empty.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ExistingEvent {
+    pub id: String,
+    #[serde(default)]
+    pub etag: Option<String>,
+    /// When the event was last modified, by anyone.
+    #[serde(default)]
+    pub updated: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub start: ExistingTime,
+    #[serde(default)]
+    pub end: ExistingTime,
+    #[serde(default)]
+    pub summary: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub location: String,
+    #[serde(rename(deserialize = "extendedProperties"), default)]
+    pub extended_properties: ExtendedProperties,
+}
+
+impl ExistingEvent {
+    /// The fingerprint of the KEATS event this was generated from, if any.
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.private(FINGERPRINT_KEY)
+    }
+
+    fn private(&self, key: &str) -> Option<&str> {
+        self.extended_properties
+            .private
+            .get(key)
+            .map(|s| s.as_str())
+    }
+
+    /// The hash of the content as generated, if tracked.
+    pub fn content(&self) -> Option<&str> {
+        self.private(CONTENT_KEY)
+    }
+
+    /// The hash of the style applied when this was generated, if any rule applied.
+    pub fn style(&self) -> Option<&str> {
+        self.private(STYLE_KEY)
+    }
+
+    /// The format version this was generated with, if known.
+    pub fn version(&self) -> Option<&str> {
+        self.private(VERSION_KEY)
+    }
+
+    /// Whether this was generated from a KEATS event, rather than created by someone else.
+    pub fn is_generated(&self) -> bool {
+        self.fingerprint().is_some()
+    }
+
+    /// Whether this may have been generated by adonais 0.1.0, which recorded nothing about events.
+    ///
+    /// Their ids were hashes, of 13 lowercase base32hex characters, unlike the longer ids Google gives.
+    /// Only the shape is checked here; the id must also match `EventInner::legacy_ids` of a KEATS event.
+    pub fn may_be_legacy(&self) -> bool {
+        !self.is_generated()
+            && self.id.len() == 13
+            && self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='v').contains(&c))
+    }
+
+    /// The identifying fields of the KEATS event this was generated from, if recorded.
+    ///
+    /// These match `EventInner::identity` of the source event, even if ids have since been derived differently.
+    pub fn provenance(&self) -> Option<(&str, i64, Option<&str>)> {
+        let code = self.private(CODE_KEY)?;
+        let start = self.start.datetime?;
+        Some((code, start.timestamp(), self.private(GROUPS_KEY)))
+    }
+
+    /// Whether the content has been changed since it was generated, e.g. notes added by hand.
+    pub fn is_edited(&self) -> bool {
+        let timestamp = |time: &ExistingTime| time.datetime.map(|datetime| datetime.timestamp());
+        match self.content() {
+            Some(generated) => {
+                generated
+                    != content_hash(
+                        &self.summary,
+                        &self.description,
+                        &self.location,
+                        timestamp(&self.start),
+                        timestamp(&self.end),
+                    )
+            }
+            // Generated before content was tracked, so edits can't be spotted
+            None => false,
+        }
+    }
+
+    /// Whether the event overlaps the window from `min` to `max`, like the Google Events List API.
+    /// Events without a time are assumed to, as they were listed for the window.
+    pub fn is_within(
+        &self,
+        min: &DateTime<FixedOffset>,
+        max: Option<&DateTime<FixedOffset>>,
+    ) -> bool {
+        let ends_after = self.end.datetime.is_none_or(|end| &end > min);
+        let starts_before = match (self.start.datetime, max) {
+            (Some(start), Some(max)) => &start < max,
+            _ => true,
+        };
+        ends_after && starts_before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_existing_event_from_json() {
+        let existing = serde_json::from_str::<ExistingEvent>(
+            r#"{
+                "kind": "calendar#event",
+                "id": "abc123",
+                "status": "confirmed",
+                "summary": "Introduction to Clinical Pharmacology, 253-256",
+                "extendedProperties": {
+                    "private": {
+                        "fingerprint": "m9p6fjn06olgm"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(existing.id, "abc123");
+        assert_eq!(existing.fingerprint(), Some("m9p6fjn06olgm"));
+
+        // Events created by hand have no properties
+        let existing = serde_json::from_str::<ExistingEvent>(r#"{"id": "abc123"}"#).unwrap();
+        assert_eq!(existing.fingerprint(), None);
+        assert!(!existing.is_generated());
+        assert!(!existing.may_be_legacy());
+
+        // Nor did events generated by adonais 0.1.0, but they had hashes for ids
+        let existing = serde_json::from_str::<ExistingEvent>(r#"{"id": "m9p6fjn06olgm"}"#).unwrap();
+        assert!(!existing.is_generated());
+        assert!(existing.may_be_legacy());
+        let existing = serde_json::from_str::<ExistingEvent>(
+            r#"{"id": "6hbm4c1o6gs34b9k68r32b9k6cqj8bb270sjgb9mcgq3ecpg6os3gdhp6s"}"#,
+        )
+        .unwrap();
+        assert!(!existing.may_be_legacy());
+    }
+
+    #[test]
+    fn test_existing_event_edits() {
+        let time = |time: &str| DateTime::parse_from_rfc3339(time).unwrap();
+        let generated = Event {
+            id: "abc123".to_owned(),
+            start: Time::from_datetime(&time("2017-11-12T14:03:00+00:00")),
+            end: Time::from_datetime(&time("2017-11-12T15:00:00+00:00")),
+            summary: "Introduction to Clinical Pharmacology, 253-256".to_owned(),
+            location: "Room 3b".to_owned(),
+            ..Default::default()
+        };
+        let json = |summary: &str| {
+            format!(
+                r#"{{
+                    "id": "abc123",
+                    "etag": "\"3181161784712000\"",
+                    "updated": "2017-11-01T09:00:00.000Z",
+                    "summary": "{}",
+                    "location": "Room 3b",
+                    "start": {{"dateTime": "2017-11-12T14:03:00Z"}},
+                    "end": {{"dateTime": "2017-11-12T15:00:00Z", "timeZone": "Europe/London"}},
+                    "extendedProperties": {{
+                        "private": {{"fingerprint": "abc", "content": "{}"}}
+                    }}
+                }}"#,
+                summary,
+                generated.content_hash()
+            )
+        };
+
+        // Times may be formatted differently, and empty fields left out
+        let existing = serde_json::from_str::<ExistingEvent>(&json(&generated.summary)).unwrap();
+        assert_eq!(existing.etag.as_deref(), Some("\"3181161784712000\""));
+        assert!(existing.is_generated());
+        assert!(!existing.may_be_legacy());
+        assert!(!existing.is_edited());
+
+        let existing =
+            serde_json::from_str::<ExistingEvent>(&json("Pharmacology (bring notes)")).unwrap();
+        assert!(existing.is_edited());
+    }
+
+    #[test]
+    fn test_event_to_json() {
+        let event = Event {
+            id: "abc123".to_owned(),
+            start: Time::from_date(&NaiveDate::from_ymd(2017, 11, 12)),
+            end: Time::from_date(&NaiveDate::from_ymd(2017, 11, 13)),
+            summary: "Exam".to_owned(),
+            color_id: Some("11".to_owned()),
+            reminders: Some(Reminders {
+                use_default: false,
+                overrides: vec![Reminder {
+                    method: ReminderMethod::Popup,
+                    minutes: 10,
+                }],
+            }),
+            transparency: Some(Transparency::Opaque),
+            visibility: Some(Visibility::Private),
+            source: Some(Source {
+                url: "https://keats.kcl.ac.uk/".to_owned(),
+                title: "KEATS".to_owned(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "id": "abc123",
+                "start": {"date": "2017-11-12"},
+                "end": {"date": "2017-11-13"},
+                "summary": "Exam",
+                "description": "",
+                "location": "",
+                "colorId": "11",
+                "reminders": {
+                    "useDefault": false,
+                    "overrides": [{"method": "popup", "minutes": 10}]
+                },
+                "transparency": "opaque",
+                "visibility": "private",
+                "source": {"url": "https://keats.kcl.ac.uk/", "title": "KEATS"},
+                "extendedProperties": {"private": {}}
+            })
+        );
+
+        // Unset fields are left out, for the calendar's defaults
+        let event = Event {
+            start: Time {
+                time_zone: Some("Europe/London".to_owned()),
+                ..Time::from_datetime(
+                    &DateTime::parse_from_rfc3339("2017-11-12T14:03:00+00:00").unwrap(),
+                )
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["start"],
+            serde_json::json!({"dateTime": "2017-11-12T14:03:00+00:00", "timeZone": "Europe/London"})
+        );
+        assert_eq!(serde_json::to_value(&event).unwrap().get("reminders"), None);
+        // Except the colour, so patches clear it
+        assert_eq!(
+            serde_json::to_value(&event).unwrap().get("colorId"),
+            Some(&serde_json::Value::Null)
+        );
+    }
+
+    #[test]
+    fn test_existing_event_is_within() {
+        let existing = serde_json::from_str::<ExistingEvent>(
+            r#"{
+                "id": "abc123",
+                "start": {"dateTime": "2017-11-12T14:00:00Z"},
+                "end": {"dateTime": "2017-11-12T15:00:00Z"}
+            }"#,
+        )
+        .unwrap();
+        let time = |time: &str| DateTime::parse_from_rfc3339(time).unwrap();
+        assert!(existing.is_within(&time("2017-11-12T14:30:00Z"), None));
+        assert!(existing.is_within(
+            &time("2017-11-01T00:00:00Z"),
+            Some(&time("2017-11-12T14:30:00Z"))
+        ));
+        assert!(!existing.is_within(&time("2017-11-12T15:00:00Z"), None));
+        assert!(!existing.is_within(
+            &time("2017-11-01T00:00:00Z"),
+            Some(&time("2017-11-12T14:00:00Z"))
+        ));
+
+        // All day events are assumed to be in the window
+        let all_day = serde_json::from_str::<ExistingEvent>(
+            r#"{"id": "abc123", "start": {"date": "2017-11-12"}, "end": {"date": "2017-11-13"}}"#,
+        )
+        .unwrap();
+        assert!(all_day.is_within(&time("2018-01-01T00:00:00Z"), None));
+    }
 }
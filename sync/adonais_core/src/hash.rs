@@ -0,0 +1,223 @@
+//! Hashes stored in calendars, which must stay the same between builds and platforms.
+//!
+//! The std `Hash` trait makes no such promise: lengths are hashed as `usize`, so wasm32 and x86_64 differ,
+//! and derived impls change with any internal struct. Instead, values are written out explicitly:
+//!
+//! - strings and lists are prefixed with their length, as a `u64`
+//! - optional values are prefixed with a presence byte, `0` or `1`
+//! - numbers are little-endian, timestamps being an `i64` of seconds
+//!
+//! Changing how anything is encoded changes every id, so it must be done with a migration.
+//! Ids from before this encoding are still recognised with `legacy_hash_ids`.
+
+use std::hash::{Hash, Hasher};
+
+use chrono::{DateTime, FixedOffset};
+use data_encoding::BASE32HEX;
+use siphasher::sip::SipHasher24;
+
+/// A value with a stable byte encoding.
+pub(crate) trait Encode {
+    fn encode(&self, bytes: &mut Vec<u8>);
+}
+
+impl Encode for str {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (self.len() as u64).encode(bytes);
+        bytes.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.as_str().encode(bytes)
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(*self);
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for i64 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// A time is its timestamp, so the same instant in another offset encodes the same.
+impl Encode for DateTime<FixedOffset> {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.timestamp().encode(bytes)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode(bytes),
+            Some(value) => {
+                1u8.encode(bytes);
+                value.encode(bytes);
+            }
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (self.len() as u64).encode(bytes);
+        for item in self {
+            item.encode(bytes);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.as_slice().encode(bytes)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (*self).encode(bytes)
+    }
+}
+
+macro_rules! encode_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode(&self, bytes: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode(bytes);)+
+            }
+        }
+    };
+}
+
+encode_tuple!(A, B);
+encode_tuple!(A, B, C);
+encode_tuple!(A, B, C, D);
+encode_tuple!(A, B, C, D, E);
+
+/// The encoding of a value, as hashed by `hash_id`.
+pub(crate) fn encode<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![];
+    value.encode(&mut bytes);
+    bytes
+}
+
+/// Convert a hash to a valid Google Event id format.
+fn to_id(hash: u64) -> String {
+    BASE32HEX
+        .encode(&hash.to_le_bytes())
+        .to_lowercase()
+        .replace("=", "")
+}
+
+/// Hash a value and convert it to a valid Google Event id format.
+pub(crate) fn hash_id<T: Encode + ?Sized>(value: &T) -> String {
+    let mut hasher = SipHasher24::new();
+    hasher.write(&encode(value));
+    to_id(hasher.finish())
+}
+
+/// A hasher writing `usize` and `isize` with a fixed number of bytes, as on a platform of that width.
+struct LegacyHasher {
+    hasher: SipHasher24,
+    width: usize,
+}
+
+impl Hasher for LegacyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.hasher.write(bytes)
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.hasher.write(&(i as u64).to_le_bytes()[..self.width])
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.hasher.write(&(i as i64).to_le_bytes()[..self.width])
+    }
+
+    fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+/// The ids adonais 0.1.0 would have given a value, by hashing it with the std `Hash` trait.
+///
+/// These depended on the platform, so there is one for wasm32, as in the browser, and one for 64 bit.
+pub(crate) fn legacy_hash_ids<T: Hash + ?Sized>(value: &T) -> Vec<String> {
+    [4, 8]
+        .iter()
+        .map(|&width| {
+            let mut hasher = LegacyHasher {
+                hasher: SipHasher24::new(),
+                width,
+            };
+            value.hash(&mut hasher);
+            to_id(hasher.finish())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("ab"), vec![2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(encode(&None::<String>), vec![0]);
+        assert_eq!(
+            encode(&Some(-2i64)),
+            vec![1, 254, 255, 255, 255, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            encode(&vec![1u32, 256]),
+            vec![2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0]
+        );
+        let start = DateTime::parse_from_rfc3339("1970-01-01T01:00:01+01:00").unwrap();
+        assert_eq!(encode(&start), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Fields can't run into each other
+        assert_ne!(encode(&("ab", Some("c"))), encode(&("a", Some("bc"))));
+        assert_ne!(
+            encode(&(None::<&str>, Some(""))),
+            encode(&(Some(""), None::<&str>))
+        );
+    }
+
+    #[test]
+    fn test_hash_id() {
+        // Pinned, as these are stored in calendars
+        assert_eq!(hash_id(""), "csivsrtrt14ug");
+        assert_eq!(
+            hash_id(&("CODE001", 1510495380i64, Some("253-256"))),
+            "viikm4g4jp6u8"
+        );
+        for id in &[hash_id("a"), hash_id(&Some("a"))] {
+            assert_eq!(id.len(), 13);
+            assert!(id
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='v').contains(&c)));
+        }
+    }
+}
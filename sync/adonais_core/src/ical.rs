@@ -0,0 +1,568 @@
+//! Export to [iCalendar (RFC 5545)](https://tools.ietf.org/html/rfc5545), for calendars other than Google.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use chrono_tz::Europe::London;
+
+use crate::hash::hash_id;
+use crate::keats::groups_parser::GroupUniverse;
+use crate::style::{Style, StyleRule};
+use crate::template::Templates;
+use crate::{filter, google, keats, Diagnostic, Event, GroupAssignment};
+
+/// The only time zone of KEATS events, with the rules since 1996.
+const LONDON_VTIMEZONE: &[&str] = &[
+    "BEGIN:VTIMEZONE",
+    "TZID:Europe/London",
+    "X-LIC-LOCATION:Europe/London",
+    "BEGIN:DAYLIGHT",
+    "TZOFFSETFROM:+0000",
+    "TZOFFSETTO:+0100",
+    "TZNAME:BST",
+    "DTSTART:19700329T010000",
+    "RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+    "END:DAYLIGHT",
+    "BEGIN:STANDARD",
+    "TZOFFSETFROM:+0100",
+    "TZOFFSETTO:+0000",
+    "TZNAME:GMT",
+    "DTSTART:19701025T020000",
+    "RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU",
+    "END:STANDARD",
+    "END:VTIMEZONE",
+];
+
+/// Content lines may be at most this many octets, not counting the line break.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Escape a `TEXT` value.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A local time in London, for properties with `TZID=Europe/London`.
+fn format_local(datetime: &DateTime<FixedOffset>) -> String {
+    datetime
+        .with_timezone(&London)
+        .format("%Y%m%dT%H%M%S")
+        .to_string()
+}
+
+fn format_utc(datetime: &DateTime<Utc>) -> String {
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Builds a calendar of CRLF separated content lines, folded to the maximum length.
+#[derive(Default)]
+struct Writer {
+    output: String,
+}
+
+impl Writer {
+    fn line(&mut self, line: &str) {
+        let mut length = 0;
+        for c in line.chars() {
+            // Never split a character, and count the space starting each continuation
+            if length + c.len_utf8() > MAX_LINE_LENGTH {
+                self.output.push_str("\r\n ");
+                length = 1;
+            }
+            self.output.push(c);
+            length += c.len_utf8();
+        }
+        self.output.push_str("\r\n");
+    }
+
+    fn property(&mut self, name: &str, value: &str) {
+        self.line(&format!("{}:{}", name, value));
+    }
+
+    fn text(&mut self, name: &str, value: &str) {
+        self.property(name, &escape_text(value));
+    }
+}
+
+/// The last exported version of an event, and its sequence number.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sequence {
+    /// A hash of the rendered event, apart from `DTSTAMP` and `SEQUENCE`.
+    pub content: String,
+    pub sequence: u32,
+    /// When the event starts. Its id depends on this, so it is only exported again while this is in the window.
+    pub start: DateTime<FixedOffset>,
+}
+
+/// How long before the window sequence numbers are kept, in case an earlier window is exported again.
+const RETENTION_WEEKS: i64 = 52;
+
+/// Sequence numbers for each event id, kept between exports.
+///
+/// Calendar apps only take changes to an event with a higher `SEQUENCE`, so it is bumped whenever the rendered
+/// event changes, whether from KEATS or from the options it is rendered with.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Sequences(BTreeMap<String, Sequence>);
+
+impl Sequences {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record the current version of each event, bumping the sequence of any that changed.
+    ///
+    /// Events no longer exported are kept, as they may come back, with changes that calendar apps must not ignore.
+    /// They are only forgotten once they started `RETENTION_WEEKS` before `time_min`.
+    pub fn update(
+        &mut self,
+        events: &[Event],
+        options: &Options,
+        time_min: &DateTime<FixedOffset>,
+    ) {
+        let retained = *time_min - Duration::weeks(RETENTION_WEEKS);
+        self.0.retain(|_, entry| entry.start >= retained);
+        for event in events {
+            let content = hash_id(&render_body(event, options));
+            let sequence = match self.0.get(&event.id) {
+                Some(entry) if entry.content == content => entry.sequence,
+                Some(entry) => entry.sequence + 1,
+                None => 0,
+            };
+            self.0.insert(
+                event.id.clone(),
+                Sequence {
+                    content,
+                    sequence,
+                    start: event.inner.start,
+                },
+            );
+        }
+    }
+
+    /// The sequence number of an event, which is 0 until it changes.
+    pub fn get(&self, id: &str) -> u32 {
+        self.0.get(id).map_or(0, |entry| entry.sequence)
+    }
+}
+
+/// How to render a calendar.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct Options {
+    /// Shown as the calendar name by apps supporting `X-WR-CALNAME`.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Reminders and transparency for matching events. Google colours have no equivalent, so are ignored.
+    #[serde(default)]
+    pub styles: Vec<StyleRule>,
+    #[serde(default)]
+    pub templates: Templates,
+}
+
+/// The unique id of an event across calendars.
+pub fn uid(event: &Event) -> String {
+    format!("{}@adonais", event.id)
+}
+
+/// The content lines of an event after `SEQUENCE`, which are the same in every export until the event changes.
+fn render_body(event: &Event, options: &Options) -> String {
+    let inner = &event.inner;
+    let mut writer = Writer::default();
+    writer.property("DTSTART;TZID=Europe/London", &format_local(&inner.start));
+    writer.property("DTEND;TZID=Europe/London", &format_local(&inner.end));
+    writer.text("SUMMARY", &options.templates.summary.render(inner));
+    for (name, value) in &[
+        ("DESCRIPTION", options.templates.description.render(inner)),
+        ("LOCATION", options.templates.location.render(inner)),
+    ] {
+        if !value.is_empty() {
+            writer.text(name, value);
+        }
+    }
+    if let Some(type_) = &inner.type_ {
+        writer.text("CATEGORIES", type_);
+    }
+
+    let style = Style::from_rules(&options.styles, inner);
+    if let Some(transparency) = style.transparency {
+        writer.property(
+            "TRANSP",
+            match transparency {
+                google::Transparency::Opaque => "OPAQUE",
+                google::Transparency::Transparent => "TRANSPARENT",
+            },
+        );
+    }
+    for minutes in style.reminders.iter().flatten() {
+        writer.line("BEGIN:VALARM");
+        writer.property("ACTION", "DISPLAY");
+        writer.property("TRIGGER", &format!("-PT{}M", minutes));
+        writer.text("DESCRIPTION", &options.templates.summary.render(inner));
+        writer.line("END:VALARM");
+    }
+    writer.output
+}
+
+fn write_event(
+    writer: &mut Writer,
+    event: &Event,
+    sequence: u32,
+    options: &Options,
+    stamp: &DateTime<Utc>,
+) {
+    writer.line("BEGIN:VEVENT");
+    writer.text("UID", &uid(event));
+    writer.property("DTSTAMP", &format_utc(stamp));
+    writer.property("SEQUENCE", &sequence.to_string());
+    writer.output.push_str(&render_body(event, options));
+    writer.line("END:VEVENT");
+}
+
+/// Render events as a `VCALENDAR`, stamped with the time of export.
+pub fn render_calendar(
+    events: &[Event],
+    sequences: &Sequences,
+    options: &Options,
+    stamp: &DateTime<Utc>,
+) -> String {
+    let mut writer = Writer::default();
+    writer.line("BEGIN:VCALENDAR");
+    writer.property("VERSION", "2.0");
+    writer.property(
+        "PRODID",
+        &format!("-//adonais//adonais_core {}//EN", env!("CARGO_PKG_VERSION")),
+    );
+    writer.property("CALSCALE", "GREGORIAN");
+    writer.property("METHOD", "PUBLISH");
+    if let Some(name) = &options.name {
+        writer.text("X-WR-CALNAME", name);
+    }
+    writer.property("X-WR-TIMEZONE", "Europe/London");
+    for line in LONDON_VTIMEZONE {
+        writer.line(line);
+    }
+    for event in events {
+        write_event(&mut writer, event, sequences.get(&event.id), options, stamp);
+    }
+    writer.line("END:VCALENDAR");
+    writer.output
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct IcalRequest {
+    /// Events obtained from the KEATS API.
+    pub new: Vec<keats::Event>,
+    /// Only export events relevant to these groups, as for a `CalendarUpdateRequest`.
+    pub groups: Vec<GroupAssignment>,
+    /// Events before this time are left out.
+    pub time_min: DateTime<FixedOffset>,
+    /// Events starting at or after this time are left out.
+    #[serde(default)]
+    pub time_max: Option<DateTime<FixedOffset>>,
+    /// Only export this many weeks after `time_min`.
+    #[serde(default)]
+    pub weeks: Option<u32>,
+    #[serde(default)]
+    pub universe: GroupUniverse,
+    #[serde(default)]
+    pub filter: filter::Filter,
+    #[serde(flatten)]
+    pub options: Options,
+    /// Sequence numbers from the previous export, if any.
+    #[serde(default)]
+    pub sequences: Sequences,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IcalResponse {
+    /// The calendar, as the contents of an `.ics` file.
+    pub ics: String,
+    /// Sequence numbers to keep for the next export.
+    pub sequences: Sequences,
+    /// KEATS events that were skipped or repaired.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Select and render the user's events, bumping the sequence of any that changed since the last export.
+pub fn calculate_ical(request: IcalRequest, stamp: &DateTime<Utc>) -> IcalResponse {
+    let time_max = crate::window_end(&request.time_min, request.time_max, request.weeks);
+    let IcalRequest {
+        new,
+        groups,
+        time_min,
+        universe,
+        filter,
+        options,
+        mut sequences,
+        ..
+    } = request;
+    let (events, diagnostics) = crate::select_events(
+        new,
+        &groups,
+        &filter,
+        &universe,
+        &time_min,
+        time_max.as_ref(),
+    );
+    sequences.update(&events, &options, &time_min);
+    IcalResponse {
+        ics: render_calendar(&events, &sequences, &options, stamp),
+        sequences,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::keats::groups_parser::Group;
+
+    fn keats_event() -> keats::Event {
+        keats::Event {
+            date: "2017-11-12T00:00:00".to_owned(),
+            start_time: "14:03".to_owned(),
+            end_time: "15:00".to_owned(),
+            code: "CODE001".to_owned(),
+            groups: Some("253-256".to_owned()),
+            title: Some("Introduction to Clinical Pharmacology".to_owned()),
+            type_: Some("Lecture".to_owned()),
+            staff: Some("John Keats".to_owned()),
+            room: Some("Room 3b".to_owned()),
+            campus: Some("Unseen University".to_owned()),
+        }
+    }
+
+    fn stamp() -> DateTime<Utc> {
+        Utc.ymd(2017, 11, 1).and_hms(9, 30, 0)
+    }
+
+    /// Undo line folding, to get back each content line.
+    fn unfold(ics: &str) -> Vec<String> {
+        assert!(ics.ends_with("\r\n"));
+        ics.trim_end_matches("\r\n")
+            .replace("\r\n ", "")
+            .split("\r\n")
+            .map(|line| line.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_escape_text() {
+        assert_eq!(
+            escape_text("Room 3b, Guy's; \\ upstairs\r\nby the lift"),
+            "Room 3b\\, Guy's\\; \\\\ upstairs\\nby the lift"
+        );
+    }
+
+    #[test]
+    fn test_fold_lines() {
+        let mut writer = Writer::default();
+        let value = "é".repeat(50);
+        writer.text("SUMMARY", &value);
+        let output = writer.output;
+        for line in output.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_LENGTH, "{:?} is too long", line);
+        }
+        assert_eq!(output.matches("\r\n ").count(), 1);
+        assert_eq!(unfold(&output), vec![format!("SUMMARY:{}", value)]);
+    }
+
+    #[test]
+    fn test_render_calendar() {
+        let event = Event::try_from(keats_event()).unwrap();
+        let options = Options {
+            name: Some("GKT Year 2".to_owned()),
+            styles: serde_json::from_str(
+                r#"[{"type": "Lecture", "reminders": [15], "transparency": "opaque"}]"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let ics = render_calendar(
+            std::slice::from_ref(&event),
+            &Sequences::new(),
+            &options,
+            &stamp(),
+        );
+        let lines = unfold(&ics);
+        let vevent_start = lines
+            .iter()
+            .position(|line| line == "BEGIN:VEVENT")
+            .unwrap();
+        assert_eq!(
+            lines[..vevent_start]
+                .iter()
+                .filter(|line| !line.starts_with("PRODID"))
+                .take(6)
+                .collect::<Vec<_>>(),
+            vec![
+                "BEGIN:VCALENDAR",
+                "VERSION:2.0",
+                "CALSCALE:GREGORIAN",
+                "METHOD:PUBLISH",
+                "X-WR-CALNAME:GKT Year 2",
+                "X-WR-TIMEZONE:Europe/London",
+            ]
+        );
+        assert!(lines[..vevent_start].contains(&"TZID:Europe/London".to_owned()));
+        let uid = format!("UID:{}@adonais", event.id);
+        assert_eq!(
+            lines[vevent_start..]
+                .iter()
+                .map(|line| line.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "BEGIN:VEVENT",
+                uid.as_str(),
+                "DTSTAMP:20171101T093000Z",
+                "SEQUENCE:0",
+                "DTSTART;TZID=Europe/London:20171112T140300",
+                "DTEND;TZID=Europe/London:20171112T150000",
+                "SUMMARY:Introduction to Clinical Pharmacology\\, 253-256",
+                "DESCRIPTION:CODE001\\nJohn Keats\\nLecture",
+                "LOCATION:Room 3b\\, Unseen University",
+                "CATEGORIES:Lecture",
+                "TRANSP:OPAQUE",
+                "BEGIN:VALARM",
+                "ACTION:DISPLAY",
+                "TRIGGER:-PT15M",
+                "DESCRIPTION:Introduction to Clinical Pharmacology\\, 253-256",
+                "END:VALARM",
+                "END:VEVENT",
+                "END:VCALENDAR",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summer_time() {
+        let event = Event::try_from(keats::Event {
+            date: "2018-06-01T00:00:00".to_owned(),
+            ..keats_event()
+        })
+        .unwrap();
+        let ics = render_calendar(&[event], &Sequences::new(), &Options::default(), &stamp());
+        // Times are local, an hour ahead of UTC
+        assert!(ics.contains("DTSTART;TZID=Europe/London:20180601T140300\r\n"));
+    }
+
+    #[test]
+    fn test_sequences() {
+        let event = Event::try_from(keats_event()).unwrap();
+        let moved = Event::try_from(keats::Event {
+            room: Some("Room 4".to_owned()),
+            ..keats_event()
+        })
+        .unwrap();
+        assert_eq!(event.id, moved.id);
+
+        let options = Options::default();
+        let time_min = DateTime::parse_from_rfc3339("2017-11-06T00:00:00+00:00").unwrap();
+        let mut sequences = Sequences::new();
+        sequences.update(std::slice::from_ref(&event), &options, &time_min);
+        assert_eq!(sequences.get(&event.id), 0);
+        sequences.update(std::slice::from_ref(&event), &options, &time_min);
+        assert_eq!(sequences.get(&event.id), 0);
+        sequences.update(std::slice::from_ref(&moved), &options, &time_min);
+        assert_eq!(sequences.get(&event.id), 1);
+        sequences.update(std::slice::from_ref(&event), &options, &time_min);
+        assert_eq!(sequences.get(&event.id), 2);
+
+        // Changing how events are rendered changes them too
+        let styled = Options {
+            styles: serde_json::from_str(r#"[{"type": "Lecture", "reminders": [15]}]"#).unwrap(),
+            ..Default::default()
+        };
+        sequences.update(std::slice::from_ref(&event), &styled, &time_min);
+        assert_eq!(sequences.get(&event.id), 3);
+        let templated = Options {
+            templates: serde_json::from_value(serde_json::json!({"summary": "{code}"})).unwrap(),
+            ..styled
+        };
+        sequences.update(std::slice::from_ref(&event), &templated, &time_min);
+        assert_eq!(sequences.get(&event.id), 4);
+        sequences.update(std::slice::from_ref(&event), &templated, &time_min);
+        assert_eq!(sequences.get(&event.id), 4);
+
+        // Events no longer exported are kept, so they are bumped if they come back changed
+        sequences.update(&[], &templated, &time_min);
+        assert_eq!(sequences.get(&event.id), 4);
+        sequences.update(std::slice::from_ref(&moved), &templated, &time_min);
+        assert_eq!(sequences.get(&event.id), 5);
+
+        // Kept between exports as JSON
+        let json = serde_json::to_string(&sequences).unwrap();
+        assert_eq!(serde_json::from_str::<Sequences>(&json).unwrap(), sequences);
+
+        // Until they are long past
+        let next_year = time_min + Duration::weeks(RETENTION_WEEKS + 1);
+        sequences.update(&[], &templated, &next_year);
+        assert_eq!(sequences, Sequences::new());
+    }
+
+    #[test]
+    fn test_calculate_ical() {
+        let request: IcalRequest = serde_json::from_value(serde_json::json!({
+            "new": [],
+            "groups": [253],
+            "time_min": "2017-11-06T00:00:00+00:00",
+            "weeks": 1,
+            "name": "GKT Year 2",
+        }))
+        .unwrap();
+        let request = IcalRequest {
+            new: vec![
+                keats_event(),
+                keats::Event {
+                    code: "CODE002".to_owned(),
+                    groups: Some("201".to_owned()),
+                    ..keats_event()
+                },
+                keats::Event {
+                    date: "2017-11-13T00:00:00".to_owned(),
+                    ..keats_event()
+                },
+                keats::Event {
+                    start_time: "spam".to_owned(),
+                    ..keats_event()
+                },
+            ],
+            ..request
+        };
+        assert_eq!(request.groups, vec![Group::from(253).into()]);
+        assert_eq!(request.options.name, Some("GKT Year 2".to_owned()));
+
+        let response = calculate_ical(request.clone(), &stamp());
+        assert_eq!(response.ics.matches("BEGIN:VEVENT").count(), 1);
+        assert_eq!(response.diagnostics.len(), 1);
+
+        // Changed events are bumped on the next export
+        let response = calculate_ical(
+            IcalRequest {
+                new: vec![keats::Event {
+                    room: Some("Room 4".to_owned()),
+                    ..keats_event()
+                }],
+                sequences: response.sequences,
+                ..request
+            },
+            &stamp(),
+        );
+        assert!(response.ics.contains("SEQUENCE:1\r\n"));
+    }
+}
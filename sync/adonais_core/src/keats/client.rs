@@ -0,0 +1,380 @@
+//! Fetching timetables from the KEATS API.
+//!
+//! URLs and response decoding are always available, so other transports (like `fetch` in the browser) can share them.
+//! Fetching over HTTP needs the `client` feature.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use super::Event;
+
+/// The public KEATS API. Each module's timetable is at `/Mod-Module.<module id>`.
+pub const BASE_URL: &str = "https://lsm-education.kcl.ac.uk/apicommonstring/api/values";
+
+const MODULE_PREFIX: &str = "Mod-Module.";
+
+/// Everything that can go wrong fetching a timetable.
+#[derive(Debug)]
+pub enum Error {
+    /// A module id was not of the form `5MBBSStage2`.
+    InvalidModule(String),
+    /// The request could not be sent, or its response read.
+    #[cfg(feature = "client")]
+    Http(reqwest::Error),
+    /// The request could not be sent by another transport, such as the browser.
+    Unavailable(String),
+    /// KEATS responded with an error.
+    Status {
+        url: String,
+        status: u16,
+        body: String,
+    },
+    /// The response was not a list of KEATS events.
+    Json(serde_json::Error),
+    /// A snapshot could not be loaded or saved.
+    Store(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidModule(id) => write!(
+                f,
+                "Invalid KEATS module '{}', expected a programme and stage like '5MBBSStage2'",
+                id
+            ),
+            #[cfg(feature = "client")]
+            Error::Http(error) => write!(f, "Could not fetch from KEATS: {}", error),
+            Error::Unavailable(reason) => write!(f, "Could not fetch from KEATS: {}", reason),
+            Error::Status { url, status, body } => {
+                write!(f, "KEATS responded to '{}' with {}: {}", url, status, body)
+            }
+            Error::Json(error) => write!(f, "Invalid KEATS events: {}", error),
+            Error::Store(error) => write!(f, "Could not access KEATS snapshot: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidModule(_) | Error::Unavailable(_) | Error::Status { .. } => None,
+            #[cfg(feature = "client")]
+            Error::Http(error) => Some(error),
+            Error::Json(error) => Some(error),
+            Error::Store(error) => Some(error),
+        }
+    }
+}
+
+/// A KEATS module, being the timetable for one stage of a programme, e.g. `5MBBSStage2`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ModuleId {
+    /// The programme code, e.g. `5MBBS`.
+    programme: String,
+    stage: u8,
+}
+
+impl ModuleId {
+    /// A module for a programme, which must be a non-empty alphanumeric code, and a stage from 1.
+    pub fn new(programme: &str, stage: u8) -> Result<Self, Error> {
+        if programme.is_empty()
+            || !programme.chars().all(|c| c.is_ascii_alphanumeric())
+            || stage == 0
+        {
+            return Err(Error::InvalidModule(format!("{}Stage{}", programme, stage)));
+        }
+        Ok(ModuleId {
+            programme: programme.to_owned(),
+            stage,
+        })
+    }
+
+    /// A stage of the five year MBBS programme.
+    pub fn mbbs(stage: u8) -> Result<Self, Error> {
+        ModuleId::new("5MBBS", stage)
+    }
+
+    pub fn programme(&self) -> &str {
+        &self.programme
+    }
+
+    pub fn stage(&self) -> u8 {
+        self.stage
+    }
+}
+
+/// MBBS stage 2, the timetable adonais was written for.
+impl Default for ModuleId {
+    fn default() -> Self {
+        ModuleId::mbbs(2).expect("valid module")
+    }
+}
+
+impl fmt::Display for ModuleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}Stage{}", self.programme, self.stage)
+    }
+}
+
+/// Parse an id like `5MBBSStage2`, optionally with the `Mod-Module.` prefix used in URLs.
+impl FromStr for ModuleId {
+    type Err = Error;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidModule(id.to_owned());
+        let unprefixed = id.strip_prefix(MODULE_PREFIX).unwrap_or(id);
+        let index = unprefixed.rfind("Stage").ok_or_else(invalid)?;
+        let (programme, stage) = (&unprefixed[..index], &unprefixed[index + "Stage".len()..]);
+        if !stage.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let stage = stage.parse().map_err(|_| invalid())?;
+        ModuleId::new(programme, stage).map_err(|_| invalid())
+    }
+}
+
+impl TryFrom<String> for ModuleId {
+    type Error = Error;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        id.parse()
+    }
+}
+
+impl From<ModuleId> for String {
+    fn from(module: ModuleId) -> Self {
+        module.to_string()
+    }
+}
+
+/// Turn an unsuccessful response into an error, keeping the body to explain it.
+pub fn check_status(url: &str, status: u16, body: String) -> Result<String, Error> {
+    if (200..300).contains(&status) {
+        Ok(body)
+    } else {
+        Err(Error::Status {
+            url: url.to_owned(),
+            status,
+            body,
+        })
+    }
+}
+
+/// Decode the body of a successful response.
+pub fn decode(body: &str) -> Result<Vec<Event>, Error> {
+    serde_json::from_str(body).map_err(Error::Json)
+}
+
+/// A row of a response that is not a KEATS event.
+#[derive(Debug)]
+pub struct UnreadableRow {
+    /// The position of the row in the response.
+    pub index: usize,
+    pub error: serde_json::Error,
+}
+
+/// Decode the rows of a response one by one, so a row that is not a KEATS event only loses that event.
+pub fn decode_rows(rows: Vec<serde_json::Value>) -> (Vec<Event>, Vec<UnreadableRow>) {
+    let mut events = vec![];
+    let mut unreadable = vec![];
+    for (index, row) in rows.into_iter().enumerate() {
+        match serde_json::from_value(row) {
+            Ok(event) => events.push(event),
+            Err(error) => unreadable.push(UnreadableRow { index, error }),
+        }
+    }
+    (events, unreadable)
+}
+
+/// A client for the KEATS API, or a stand-in for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Client {
+    base_url: String,
+}
+
+impl Client {
+    /// A client for the API at `base_url`, under which modules are at `/Mod-Module.<module id>`.
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// The URL of a module's timetable.
+    pub fn url(&self, module: &ModuleId) -> String {
+        format!("{}/{}{}", self.base_url, MODULE_PREFIX, module)
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(BASE_URL)
+    }
+}
+
+#[cfg(feature = "client")]
+impl Client {
+    /// Fetch a module's timetable as JSON, without decoding it.
+    pub fn fetch_json(&self, module: &ModuleId) -> Result<String, Error> {
+        let url = self.url(module);
+        let response = reqwest::blocking::get(&url).map_err(Error::Http)?;
+        let status = response.status().as_u16();
+        let body = response.text().map_err(Error::Http)?;
+        check_status(&url, status, body)
+    }
+
+    /// Fetch a module's timetable.
+    pub fn fetch(&self, module: &ModuleId) -> Result<Vec<Event>, Error> {
+        decode(&self.fetch_json(module)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_id() {
+        let stage2 = ModuleId::default();
+        assert_eq!(stage2.to_string(), "5MBBSStage2");
+        assert_eq!("5MBBSStage2".parse::<ModuleId>().unwrap(), stage2);
+        assert_eq!(
+            "Mod-Module.5MBBSStage2".parse::<ModuleId>().unwrap(),
+            stage2
+        );
+
+        let other = "4GEPStage10".parse::<ModuleId>().unwrap();
+        assert_eq!(other.programme(), "4GEP");
+        assert_eq!(other.stage(), 10);
+        assert_eq!(ModuleId::mbbs(3).unwrap().to_string(), "5MBBSStage3");
+
+        for invalid in &[
+            "",
+            "5MBBS",
+            "Stage2",
+            "5MBBSStage",
+            "5MBBSStage0",
+            "5MBBSStage+2",
+            "5MBBSStage999",
+            "5MBBS/../Stage2",
+        ] {
+            match invalid.parse::<ModuleId>() {
+                Err(Error::InvalidModule(id)) => assert_eq!(&id, invalid),
+                other => panic!("Expected '{}' to be invalid, got {:?}", invalid, other),
+            }
+        }
+        assert!(ModuleId::new("5 MBBS", 2).is_err());
+    }
+
+    #[test]
+    fn test_module_id_serde() {
+        let module: ModuleId = serde_json::from_str(r#""5MBBSStage4""#).unwrap();
+        assert_eq!(module, ModuleId::mbbs(4).unwrap());
+        assert_eq!(serde_json::to_string(&module).unwrap(), r#""5MBBSStage4""#);
+        assert!(serde_json::from_str::<ModuleId>(r#""5MBBS""#).is_err());
+    }
+
+    #[test]
+    fn test_client_url() {
+        assert_eq!(
+            Client::default().url(&ModuleId::default()),
+            "https://lsm-education.kcl.ac.uk/apicommonstring/api/values/Mod-Module.5MBBSStage2"
+        );
+        assert_eq!(
+            Client::new("http://127.0.0.1:8000/values/").url(&ModuleId::mbbs(1).unwrap()),
+            "http://127.0.0.1:8000/values/Mod-Module.5MBBSStage1"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("[]").unwrap(), vec![]);
+        let events = decode(
+            r#"[{"C": "5MBBS101", "Date": "2019-09-09T00:00:00", "N": null, "T": null,
+                 "ST": "09:00", "ET": "10:00", "G": null, "S": null, "R": null, "CP": null}]"#,
+        )
+        .unwrap();
+        assert_eq!(events[0].code, "5MBBS101");
+
+        match decode("<html>Service unavailable</html>") {
+            Err(Error::Json(_)) => {}
+            other => panic!("Expected a JSON error, got {:?}", other),
+        }
+        match check_status("http://keats", 503, "Down".to_owned()) {
+            Err(Error::Status {
+                status: 503, body, ..
+            }) => assert_eq!(body, "Down"),
+            other => panic!("Expected a status error, got {:?}", other),
+        }
+        assert_eq!(
+            check_status("http://keats", 200, "[]".to_owned()).unwrap(),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn test_decode_rows() {
+        let rows = serde_json::from_str(
+            r#"[{"C": "5MBBS101", "Date": "2019-09-09T00:00:00", "N": null, "T": null,
+                 "ST": "09:00", "ET": "10:00", "G": null, "S": null, "R": null, "CP": null},
+                {"C": 5, "Date": "2019-09-09T00:00:00"}]"#,
+        )
+        .unwrap();
+        let (events, unreadable) = decode_rows(rows);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, "5MBBS101");
+        assert_eq!(unreadable.len(), 1);
+        assert_eq!(unreadable[0].index, 1);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_fetch() {
+        use std::thread;
+
+        use tiny_http::{Response, Server};
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let client = Client::new(&format!("http://{}/values", server.server_addr()));
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match request.url() {
+                    "/values/Mod-Module.5MBBSStage2" => Response::from_string(
+                        r#"[{"C": "5MBBS201-OW", "Date": "2019-09-09T00:00:00", "N": null, "T": null,
+                             "ST": "09:00", "ET": "12:30", "G": "201-289", "S": null, "R": null, "CP": null}]"#,
+                    ),
+                    "/values/Mod-Module.5MBBSStage3" => {
+                        Response::from_string("{\"Message\": \"Error\"}")
+                    }
+                    _ => Response::from_string("Not found").with_status_code(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let events = client.fetch(&ModuleId::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].groups.as_deref(), Some("201-289"));
+
+        match client.fetch(&ModuleId::mbbs(3).unwrap()) {
+            Err(Error::Json(_)) => {}
+            other => panic!("Expected a JSON error, got {:?}", other),
+        }
+        match client.fetch(&ModuleId::mbbs(4).unwrap()) {
+            Err(Error::Status { url, status, body }) => {
+                assert!(url.ends_with("/values/Mod-Module.5MBBSStage4"));
+                assert_eq!(status, 404);
+                assert_eq!(body, "Not found");
+            }
+            other => panic!("Expected a 404, got {:?}", other),
+        }
+        match Client::new("http://127.0.0.1:1").fetch(&ModuleId::default()) {
+            Err(Error::Http(_)) => {}
+            other => panic!("Expected an HTTP error, got {:?}", other),
+        }
+    }
+}
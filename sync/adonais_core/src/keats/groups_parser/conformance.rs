@@ -0,0 +1,236 @@
+//! Checks every enabled parser backend against a shared corpus, a reference model and each other.
+
+use std::collections::BTreeSet;
+
+use proptest::prelude::*;
+
+use super::*;
+
+/// Every parser backend compiled into this build.
+fn backends() -> Vec<(&'static str, Tree)> {
+    vec![
+        #[cfg(feature = "parser_combine")]
+        ("combine", parser_combine::tree),
+        #[cfg(feature = "parser_nom")]
+        ("nom", parser_nom::tree),
+        #[cfg(feature = "parser_pest")]
+        ("pest", parser_pest::tree),
+    ]
+}
+
+/// Inputs, with either the groups they contain or the offset and token of the error.
+type Case = (
+    &'static str,
+    Result<&'static [&'static str], (usize, &'static str)>,
+);
+
+const CORPUS: &[Case] = &[
+    // Examples from real data
+    ("0", Ok(&["0"])),
+    ("0-2", Ok(&["0", "1", "2"])),
+    ("0, 7", Ok(&["0", "7"])),
+    ("0, 7-10", Ok(&["0", "7", "8", "9", "10"])),
+    ("200, 210 220", Ok(&["200", "210", "220"])),
+    (
+        "210 - 212, 217-218",
+        Ok(&["210", "211", "212", "217", "218"]),
+    ),
+    ("3 7-9, 10", Ok(&["3", "7", "8", "9", "10"])),
+    ("121,123 - 125   , 121", Ok(&["121", "123", "124", "125"])),
+    ("121 123 - 125 121", Ok(&["121", "123", "124", "125"])),
+    // Whitespace
+    ("261 ", Ok(&["261"])),
+    (" 261", Ok(&["261"])),
+    ("1 ,\t2", Ok(&["1", "2"])),
+    ("1\t-\t3", Ok(&["1", "2", "3"])),
+    ("1  2", Err((3, "2"))),
+    ("1\t2", Err((2, "2"))),
+    // Limits
+    ("4294967295", Ok(&["4294967295"])),
+    ("4294967296", Err((0, "4294967296"))),
+    ("1, 4294967296", Err((3, "4294967296"))),
+    ("1-4294967296", Err((2, "4294967296"))),
+    ("1 - 4294967296", Err((4, "4294967296"))),
+    ("4294967296-1", Err((0, "4294967296-1"))),
+    ("A4294967296-A1", Err((11, "-A1"))),
+    ("A1-A4294967296", Err((2, "-A4294967296"))),
+    ("3-1", Err((0, "3-1"))),
+    ("1, 3 - 1", Err((3, "3"))),
+    ("A1-A1001", Err((0, "A1-A1001"))),
+    ("1, A3-1", Err((3, "A3-1"))),
+    ("A1-A4000000000", Err((0, "A1-A4000000000"))),
+    // Open ranges
+    ("297-", Ok(&["297", "298", "299"])),
+    ("297 -, 1", Ok(&["1", "297", "298", "299"])),
+    ("300-", Err((0, "300-"))),
+    ("1 except 300-", Err((9, "300-"))),
+    // Labels
+    ("253a", Ok(&["253a"])),
+    ("A1, 253", Ok(&["253", "A1"])),
+    ("A1-A3", Ok(&["A1", "A2", "A3"])),
+    ("B2 - 3", Ok(&["B2", "B3"])),
+    ("A1-B3", Err((2, "-B3"))),
+    ("A1 - B3", Err((3, "-"))),
+    ("1 A-B", Err((2, "A-B"))),
+    ("253ab1", Err((5, "1"))),
+    // Exclusions
+    ("201-205 except 203", Ok(&["201", "202", "204", "205"])),
+    ("201-205\tEXCEPT 203-204, 299", Ok(&["201", "202", "205"])),
+    ("298- except A1", Ok(&["298", "299"])),
+    ("A1-A3 except A2", Ok(&["A1", "A3"])),
+    ("201 except", Err((4, "except"))),
+    ("201,except 202", Err((4, "except"))),
+    ("201 except 4294967296", Err((4, "except"))),
+    ("201 except 202, 4294967296", Err((16, "4294967296"))),
+    ("201 except 202 except 203", Err((15, "except"))),
+    // Invalid syntax
+    ("", Err((0, ""))),
+    ("   ", Err((3, ""))),
+    ("spam", Err((0, "spam"))),
+    ("0, 297-spam", Err((7, "spam"))),
+    ("0, spam-201", Err((3, "spam-201"))),
+    ("250, spam", Err((5, "spam"))),
+    ("121,", Err((3, ","))),
+    ("1,,2", Err((2, ","))),
+    ("1-2-3", Err((3, "-3"))),
+    ("-1", Err((0, "-1"))),
+];
+
+#[test]
+fn test_corpus() {
+    for (name, tree) in backends() {
+        for (input, expected) in CORPUS {
+            let expected = expected
+                .map(|groups| {
+                    groups
+                        .iter()
+                        .cloned()
+                        .map(Group::from)
+                        .collect::<GroupSet>()
+                })
+                .map_err(|(offset, token)| GroupParseError {
+                    offset,
+                    token: token.to_owned(),
+                });
+            assert_eq!(
+                parse_groups_with(input, tree, &GroupUniverse::default()),
+                expected,
+                "backend {} on {:?}",
+                name,
+                input
+            );
+        }
+    }
+}
+
+/// A part of a valid groups string, as the reference model.
+#[derive(Clone, Debug)]
+enum ModelPart {
+    Single(u32),
+    Range(u32, u32),
+}
+
+impl ModelPart {
+    fn render(&self, dash: &str) -> String {
+        match self {
+            ModelPart::Single(group) => group.to_string(),
+            ModelPart::Range(start, end) => format!("{}{}{}", start, dash, end),
+        }
+    }
+
+    fn groups(&self) -> Vec<u32> {
+        match self {
+            ModelPart::Single(group) => vec![*group],
+            ModelPart::Range(start, end) => (*start..=*end).collect(),
+        }
+    }
+}
+
+fn model_part() -> impl Strategy<Value = ModelPart> {
+    prop_oneof![
+        (0u32..1000).prop_map(ModelPart::Single),
+        (0u32..1000, 0u32..20).prop_map(|(start, length)| ModelPart::Range(start, start + length)),
+    ]
+}
+
+fn separator() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(vec![",", ", ", " ,", " , ", ",\t", "  ,  ", " "])
+}
+
+fn dash() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(vec!["-", " - ", " -", "- ", "\t-\t"])
+}
+
+fn group() -> impl Strategy<Value = Group> {
+    prop_oneof![
+        (0u32..300).prop_map(Group::Number),
+        ("[A-C]", 0u32..30)
+            .prop_map(|(prefix, number)| Group::Label(format!("{}{}", prefix, number))),
+        "[A-C][0-9]{1,2}[a-c]?|[0-9]{1,3}[a-c]{1,2}".prop_map(Group::Label),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn prop_backends_match_model(
+        parts in prop::collection::vec((model_part(), separator(), dash()), 1..10),
+    ) {
+        let mut input = String::new();
+        for (index, (part, separator, dash)) in parts.iter().enumerate() {
+            if index > 0 {
+                input.push_str(separator);
+            }
+            input.push_str(&part.render(dash));
+        }
+        let expected: GroupSet = parts
+            .iter()
+            .flat_map(|(part, _, _)| part.groups())
+            .map(Group::Number)
+            .collect::<BTreeSet<Group>>()
+            .into_iter()
+            .collect();
+
+        for (name, tree) in backends() {
+            prop_assert_eq!(
+                parse_groups_with(&input, tree, &GroupUniverse::default()),
+                Ok(expected.clone()),
+                "backend {} on {:?}",
+                name,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn prop_backends_agree(input in "([0-9aA ,\t-]|except ){0,16}") {
+        let results: Vec<(&str, Result<Vec<Part>, GroupParseError>)> = backends()
+            .into_iter()
+            .map(|(name, tree)| (name, parse_parts_with(&input, tree)))
+            .collect();
+
+        for (name, result) in results.iter() {
+            // Errors point at the token in the input
+            if let Err(error) = result {
+                prop_assert!(input[error.offset..].starts_with(&error.token));
+            }
+            prop_assert_eq!(result, &results[0].1, "backend {} on {:?}", name, input);
+        }
+    }
+
+    #[test]
+    fn prop_format_round_trips(
+        groups in prop::collection::btree_set(group(), 1..20),
+    ) {
+        let groups: GroupSet = groups.into_iter().collect();
+        let formatted = format_groups(&groups);
+        for (name, tree) in backends() {
+            prop_assert_eq!(
+                parse_groups_with(&formatted, tree, &GroupUniverse::default()),
+                Ok(groups.clone()),
+                "backend {} on {:?}",
+                name,
+                formatted
+            );
+        }
+    }
+}
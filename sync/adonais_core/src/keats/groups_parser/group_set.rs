@@ -0,0 +1,301 @@
+use std::collections::BTreeSet;
+use std::iter::FromIterator;
+
+use super::Group;
+
+/// A set of groups, with numbered groups stored as ranges so that whole cohorts stay small.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct GroupSet {
+    /// Sorted inclusive ranges, with a gap between each.
+    ranges: Vec<(u32, u32)>,
+    labels: BTreeSet<String>,
+}
+
+/// Sort and merge overlapping or adjacent ranges.
+fn normalise(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                *last_end = end.max(*last_end)
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+impl GroupSet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Every numbered group from `start` to `end` inclusive, which is empty if `end < start`.
+    pub fn from_range(start: u32, end: u32) -> Self {
+        let mut set = GroupSet::new();
+        set.insert_range(start, end);
+        set
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.labels.is_empty()
+    }
+
+    pub fn contains(&self, group: &Group) -> bool {
+        match group {
+            Group::Number(number) => {
+                // The only range that could contain it is the last one starting at or before it
+                let index = self.ranges.partition_point(|(start, _)| start <= number);
+                index > 0 && self.ranges[index - 1].1 >= *number
+            }
+            Group::Label(label) => self.labels.contains(label),
+        }
+    }
+
+    pub fn insert(&mut self, group: Group) {
+        match group {
+            Group::Number(number) => self.insert_range(number, number),
+            Group::Label(label) => {
+                self.labels.insert(label);
+            }
+        }
+    }
+
+    /// Insert every numbered group from `start` to `end` inclusive.
+    pub fn insert_range(&mut self, start: u32, end: u32) {
+        if start <= end {
+            self.ranges.push((start, end));
+            self.ranges = normalise(std::mem::take(&mut self.ranges));
+        }
+    }
+
+    pub fn union(&self, other: &GroupSet) -> GroupSet {
+        GroupSet {
+            ranges: normalise(self.ranges.iter().chain(&other.ranges).cloned().collect()),
+            labels: self.labels.union(&other.labels).cloned().collect(),
+        }
+    }
+
+    pub fn intersection(&self, other: &GroupSet) -> GroupSet {
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while let (Some(&(a_start, a_end)), Some(&(b_start, b_end))) =
+            (self.ranges.get(i), other.ranges.get(j))
+        {
+            let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+            if start <= end {
+                ranges.push((start, end));
+            }
+            // Move past whichever range finishes first
+            if a_end < b_end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        GroupSet {
+            ranges,
+            labels: self.labels.intersection(&other.labels).cloned().collect(),
+        }
+    }
+
+    pub fn difference(&self, other: &GroupSet) -> GroupSet {
+        let mut ranges = Vec::new();
+        for &(start, end) in &self.ranges {
+            // The start of what is left of this range, if anything
+            let mut rest = Some(start);
+            for &(other_start, other_end) in &other.ranges {
+                let current = match rest {
+                    Some(current) if other_start <= end => current,
+                    _ => break,
+                };
+                if other_end < current {
+                    continue;
+                }
+                if other_start > current {
+                    ranges.push((current, other_start - 1));
+                }
+                rest = if other_end < end {
+                    Some(other_end + 1)
+                } else {
+                    None
+                };
+            }
+            if let Some(current) = rest {
+                ranges.push((current, end));
+            }
+        }
+        GroupSet {
+            ranges,
+            labels: self.labels.difference(&other.labels).cloned().collect(),
+        }
+    }
+
+    /// The numbered groups, as sorted inclusive ranges with a gap between each.
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().cloned()
+    }
+
+    /// The labelled groups, sorted.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.labels.iter().map(|label| label.as_str())
+    }
+
+    /// Every group, in the same order as `Group` sorts.
+    pub fn iter(&self) -> impl Iterator<Item = Group> + '_ {
+        self.ranges
+            .iter()
+            .flat_map(|&(start, end)| (start..=end).map(Group::Number))
+            .chain(self.labels.iter().cloned().map(Group::Label))
+    }
+}
+
+impl Extend<Group> for GroupSet {
+    fn extend<T: IntoIterator<Item = Group>>(&mut self, iter: T) {
+        for group in iter {
+            match group {
+                Group::Number(number) => self.ranges.push((number, number)),
+                Group::Label(label) => {
+                    self.labels.insert(label);
+                }
+            }
+        }
+        self.ranges = normalise(std::mem::take(&mut self.ranges));
+    }
+}
+
+impl FromIterator<Group> for GroupSet {
+    fn from_iter<T: IntoIterator<Item = Group>>(iter: T) -> Self {
+        let mut set = GroupSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn numbers(numbers: &[u32]) -> GroupSet {
+        numbers.iter().cloned().map(Group::Number).collect()
+    }
+
+    #[test]
+    fn test_ranges_are_merged() {
+        let set = numbers(&[5, 1, 2, 3, 9, 4, 10]);
+        assert_eq!(set.ranges().collect::<Vec<_>>(), vec![(1, 5), (9, 10)]);
+        assert_eq!(set, GroupSet::from_range(1, 5).union(&numbers(&[9, 10])));
+        assert!(GroupSet::from_range(5, 1).is_empty());
+
+        let mut set = GroupSet::from_range(u32::MAX - 1, u32::MAX);
+        set.insert_range(0, u32::MAX);
+        assert_eq!(set.ranges().collect::<Vec<_>>(), vec![(0, u32::MAX)]);
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut set = numbers(&[1, 2, 3, 7]);
+        set.insert(Group::from("A1"));
+        for number in &[1, 2, 3, 7] {
+            assert!(set.contains(&Group::Number(*number)));
+        }
+        for number in &[0, 4, 6, 8] {
+            assert!(!set.contains(&Group::Number(*number)));
+        }
+        assert!(set.contains(&Group::from("A1")));
+        assert!(!set.contains(&Group::from("A2")));
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = GroupSet::from_range(1, 10).union(&numbers(&[20]));
+        let b = numbers(&[0, 3, 4, 5, 10, 11, 20]);
+        assert_eq!(
+            a.intersection(&b).iter().collect::<Vec<_>>(),
+            numbers(&[3, 4, 5, 10, 20]).iter().collect::<Vec<_>>()
+        );
+        assert_eq!(a.difference(&b), numbers(&[1, 2, 6, 7, 8, 9]));
+        assert_eq!(b.difference(&a), numbers(&[0, 11]));
+        assert_eq!(
+            a.union(&b),
+            GroupSet::from_range(0, 11).union(&numbers(&[20]))
+        );
+
+        let labels: GroupSet = vec![Group::from("A1"), Group::from("B1")]
+            .into_iter()
+            .collect();
+        let other: GroupSet = vec![Group::from("B1"), Group::from(1)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            labels.intersection(&other),
+            vec![Group::from("B1")].into_iter().collect()
+        );
+        assert_eq!(
+            labels.difference(&other),
+            vec![Group::from("A1")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_iter() {
+        let set: GroupSet = vec![Group::from("A1"), Group::from(3), Group::from(1)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            vec![Group::from(1), Group::from(3), Group::from("A1")]
+        );
+    }
+
+    fn ranges() -> impl Strategy<Value = Vec<(u32, u32)>> {
+        prop::collection::vec((0u32..50, 0u32..10), 0..6).prop_map(|ranges| {
+            ranges
+                .into_iter()
+                .map(|(start, length)| (start, start + length))
+                .collect()
+        })
+    }
+
+    fn from_ranges(ranges: &[(u32, u32)]) -> (GroupSet, BTreeSet<u32>) {
+        let mut set = GroupSet::new();
+        let mut model = BTreeSet::new();
+        for &(start, end) in ranges {
+            set.insert_range(start, end);
+            model.extend(start..=end);
+        }
+        (set, model)
+    }
+
+    fn to_model(set: &GroupSet) -> BTreeSet<u32> {
+        set.iter()
+            .map(|group| match group {
+                Group::Number(number) => number,
+                Group::Label(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_btree_set(a in ranges(), b in ranges()) {
+            let (a, a_model) = from_ranges(&a);
+            let (b, b_model) = from_ranges(&b);
+            prop_assert_eq!(to_model(&a), a_model.clone());
+            prop_assert_eq!(to_model(&a.union(&b)), &a_model | &b_model);
+            prop_assert_eq!(to_model(&a.intersection(&b)), &a_model & &b_model);
+            prop_assert_eq!(to_model(&a.difference(&b)), &a_model - &b_model);
+            for number in 0..65 {
+                prop_assert_eq!(a.contains(&Group::Number(number)), a_model.contains(&number));
+            }
+            // Equal sets have equal representations
+            let canonical = |model: BTreeSet<u32>| model.into_iter().map(Group::Number).collect::<GroupSet>();
+            prop_assert_eq!(a.intersection(&b), canonical(&a_model & &b_model));
+            prop_assert_eq!(a.difference(&b), canonical(&a_model - &b_model));
+            prop_assert_eq!(canonical(a_model), a);
+        }
+    }
+}
@@ -1,13 +1,95 @@
+// Backends overridden by another enabled backend are still compiled, but unused
+#[cfg(feature = "parser_combine")]
+#[cfg_attr(feature = "parser_pest", allow(dead_code))]
+mod parser_combine;
 #[cfg(feature = "parser_nom")]
+#[cfg_attr(
+    any(feature = "parser_combine", feature = "parser_pest"),
+    allow(dead_code)
+)]
 mod parser_nom;
 #[cfg(feature = "parser_pest")]
 mod parser_pest;
 
-#[cfg(feature = "parser_nom")]
+// If several backends are enabled, prefer those that are not the default
+#[cfg(all(feature = "parser_combine", not(feature = "parser_pest")))]
+use parser_combine::tree;
+#[cfg(all(
+    feature = "parser_nom",
+    not(any(feature = "parser_combine", feature = "parser_pest"))
+))]
 use parser_nom::tree;
 #[cfg(feature = "parser_pest")]
 use parser_pest::tree;
 
+#[cfg(not(any(
+    feature = "parser_combine",
+    feature = "parser_nom",
+    feature = "parser_pest"
+)))]
+compile_error!("One of the parser_combine, parser_nom or parser_pest features must be enabled.");
+
+#[cfg(test)]
+mod conformance;
+mod group_set;
+
+pub use group_set::GroupSet;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// A single group an event is for.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(untagged, from = "GroupRepr")]
+pub enum Group {
+    Number(u32),
+    /// A group with letters in its name, e.g. `253a` or `A1`.
+    Label(String),
+}
+
+/// Groups may be given as JSON numbers or strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GroupRepr {
+    Number(u32),
+    Name(String),
+}
+
+impl From<GroupRepr> for Group {
+    fn from(repr: GroupRepr) -> Self {
+        match repr {
+            GroupRepr::Number(number) => Group::Number(number),
+            GroupRepr::Name(name) => Group::from(name.as_str()),
+        }
+    }
+}
+
+impl From<u32> for Group {
+    fn from(number: u32) -> Self {
+        Group::Number(number)
+    }
+}
+
+impl From<&str> for Group {
+    /// Names that are entirely digits are numbers, anything else is a label.
+    fn from(name: &str) -> Self {
+        match name.parse() {
+            Ok(number) if name.chars().all(|c| c.is_ascii_digit()) => Group::Number(number),
+            _ => Group::Label(name.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Group::Number(number) => write!(f, "{}", number),
+            Group::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Range {
     pub start: u32,
@@ -18,55 +100,502 @@ pub struct Range {
 pub enum Part {
     Single(u32),
     Range(Range),
+    /// From a group to the end of the universe, e.g. `250-`.
+    OpenRange(u32),
+    /// A group with letters in its name, e.g. `253a`.
+    Label(String),
+    /// Labelled groups sharing a prefix, e.g. `A1-A4`, of at most `MAX_LABEL_RANGE` groups.
+    LabelRange {
+        prefix: String,
+        range: Range,
+    },
+    /// Groups to leave out, e.g. `except 253`.
+    Exclude(Box<Part>),
+}
+
+/// The most groups a labelled range like `A1-A4` may have.
+///
+/// Labels are stored one by one, unlike numbers, so a range like `A1-A4000000000` would never finish.
+pub const MAX_LABEL_RANGE: u32 = 1000;
+
+/// Signature of the `tree` function each parser backend provides.
+type Tree = fn(&str) -> Result<Vec<Part>, GroupParseError>;
+
+/// Where, and on what, parsing a groups string failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupParseError {
+    /// Byte offset of the unexpected token in the input.
+    pub offset: usize,
+    /// The unexpected token. Empty if input ended unexpectedly.
+    pub token: String,
+}
+
+impl GroupParseError {
+    /// An error at the first token of `rest`, which must be a suffix of `input`.
+    pub fn at(input: &str, rest: &str) -> Self {
+        let rest = rest.trim_start();
+        let token_length = rest
+            .find(|c: char| c == ',' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        // Always show at least one character, even if it is a delimiter
+        let token_length = match (token_length, rest.chars().next()) {
+            (0, Some(c)) => c.len_utf8(),
+            _ => token_length,
+        };
+        GroupParseError {
+            offset: input.len() - rest.len(),
+            token: rest[..token_length].to_owned(),
+        }
+    }
+
+    /// An error for `leftover` input, which must be a suffix of `input`, after parsing a list.
+    ///
+    /// A dangling delimiter is skipped, to point at what failed to parse after it.
+    pub fn leftover(input: &str, leftover: &str) -> Self {
+        let rest = leftover.trim_start();
+        let rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        if rest.is_empty() {
+            GroupParseError::at(input, leftover)
+        } else {
+            GroupParseError::at(input, rest)
+        }
+    }
 }
 
-/// Parse a collection of ints of the form `1-3, 5`.
-/// If invalid syntax, default to 200 - 299.
-pub fn parse_group_range(range: &str) -> Vec<u32> {
-    if range.len() == 0 {
-        return (200..300).collect();
+impl fmt::Display for GroupParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.token.is_empty() {
+            write!(f, "unexpected end of input at byte {}", self.offset)
+        } else {
+            write!(f, "unexpected '{}' at byte {}", self.token, self.offset)
+        }
     }
+}
+
+impl std::error::Error for GroupParseError {}
 
-    let mut items: Vec<u32> = vec![];
-    for part in tree(range) {
+/// The inclusive range of groups in a cohort.
+///
+/// Used as the audience of an event that doesn't specify its groups.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct GroupUniverse {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Default for GroupUniverse {
+    /// The groups of the GKT Year 2 cohort.
+    fn default() -> Self {
+        GroupUniverse {
+            start: 200,
+            end: 299,
+        }
+    }
+}
+
+impl GroupUniverse {
+    /// Every group in the universe.
+    pub fn groups(&self) -> GroupSet {
+        GroupSet::from_range(self.start, self.end)
+    }
+}
+
+/// Parse a universe like `200-299`, as given on the command line.
+impl FromStr for GroupUniverse {
+    type Err = GroupParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let dash = input
+            .find('-')
+            .ok_or_else(|| GroupParseError::at(input, ""))?;
+        let bound = |part: &str, rest: &str| {
+            part.trim()
+                .parse::<u32>()
+                .map_err(|_| GroupParseError::at(input, rest))
+        };
+        let start = bound(&input[..dash], input)?;
+        let end = bound(&input[dash + 1..], &input[dash + 1..])?;
+        if end < start {
+            return Err(GroupParseError::at(input, &input[dash + 1..]));
+        }
+        Ok(GroupUniverse { start, end })
+    }
+}
+
+/// Strictly parse a collection of groups of the form `1-3, 5, A1-A4 except 2`.
+///
+/// Open ranges like `250-` end with the universe.
+/// Surrounding whitespace is ignored, but anything else unexpected is an error.
+pub fn parse_groups(input: &str, universe: &GroupUniverse) -> Result<GroupSet, GroupParseError> {
+    parse_groups_with(input, tree, universe)
+}
+
+/// Parse groups into their parts with a specific parser backend.
+fn parse_parts_with(input: &str, tree: Tree) -> Result<Vec<Part>, GroupParseError> {
+    let trimmed = input.trim();
+    let leading = input.len() - input.trim_start().len();
+    let located = |error: GroupParseError| GroupParseError {
+        offset: error.offset + leading,
+        ..error
+    };
+    if trimmed.is_empty() {
+        return Err(located(GroupParseError::at(trimmed, "")));
+    }
+    tree(trimmed).map_err(located)
+}
+
+/// An error at a range starting with `start`, for ranges that parse but refer to no groups, or too many.
+///
+/// Parts don't keep their position, so the range is found again as `start` followed by a dash.
+fn range_error(input: &str, start: &str) -> GroupParseError {
+    let offset = input
+        .match_indices(start)
+        .map(|(offset, _)| offset)
+        .find(|&offset| {
+            let before = input[..offset].chars().next_back();
+            let after = input[offset + start.len()..].trim_start();
+            !before.is_some_and(|c| c.is_ascii_alphanumeric()) && after.starts_with('-')
+        })
+        .unwrap_or(0);
+    GroupParseError::at(input, &input[offset..])
+}
+
+/// Add the groups a part of `input` refers to into `groups`.
+///
+/// Ranges that are reversed, open ranges starting after the universe, and labelled ranges that are too long
+/// are errors, rather than silently referring to no groups.
+fn resolve_part(
+    part: Part,
+    input: &str,
+    universe: &GroupUniverse,
+    groups: &mut GroupSet,
+) -> Result<(), GroupParseError> {
+    match part {
+        Part::Single(number) => groups.insert(Group::Number(number)),
+        Part::Range(Range { start, end }) if end < start => {
+            return Err(range_error(input, &start.to_string()))
+        }
+        Part::Range(Range { start, end }) => groups.insert_range(start, end),
+        Part::OpenRange(start) if universe.end < start => {
+            return Err(range_error(input, &start.to_string()))
+        }
+        Part::OpenRange(start) => groups.insert_range(start, universe.end),
+        Part::Label(label) => groups.insert(Group::Label(label)),
+        Part::LabelRange {
+            prefix,
+            range: Range { start, end },
+        } => {
+            if end < start || end - start >= MAX_LABEL_RANGE {
+                return Err(range_error(input, &format!("{}{}", prefix, start)));
+            }
+            groups.extend((start..=end).map(|number| Group::Label(format!("{}{}", prefix, number))))
+        }
+        Part::Exclude(part) => resolve_part(*part, input, universe, groups)?,
+    }
+    Ok(())
+}
+
+/// Parse groups with a specific parser backend.
+fn parse_groups_with(
+    input: &str,
+    tree: Tree,
+    universe: &GroupUniverse,
+) -> Result<GroupSet, GroupParseError> {
+    let mut included = GroupSet::new();
+    let mut excluded = GroupSet::new();
+    for part in parse_parts_with(input, tree)? {
         match part {
-            Part::Range(Range { start, end }) => items.extend(start..(end + 1)),
-            Part::Single(group) => items.push(group),
+            Part::Exclude(part) => resolve_part(*part, input, universe, &mut excluded)?,
+            part => resolve_part(part, input, universe, &mut included)?,
+        }
+    }
+    Ok(included.difference(&excluded))
+}
+
+/// Leniently parse a collection of groups of the form `1-3, 5, A1-A4 except 2`.
+/// If empty or invalid syntax, default to every group in the universe.
+pub fn parse_group_range(range: &str, universe: &GroupUniverse) -> GroupSet {
+    parse_groups(range, universe).unwrap_or_else(|_| universe.groups())
+}
+
+/// Split a label like `A12` into its prefix and number, if it can be part of a labelled range.
+fn split_label(label: &str) -> Option<(&str, u32)> {
+    let digits = label.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let prefix = &label[..label.len() - digits.len()];
+    let number: u32 = digits.parse().ok()?;
+    // Leading zeros would be lost in a range
+    if prefix.is_empty() || number.to_string() != digits {
+        return None;
+    }
+    Some((prefix, number))
+}
+
+/// Collapse sorted numbers into inclusive runs of consecutive numbers.
+fn runs(numbers: impl IntoIterator<Item = u32>) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for number in numbers {
+        match runs.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(number) => *end = number,
+            _ => runs.push((number, number)),
+        }
+    }
+    runs
+}
+
+fn format_run(prefix: &str, (start, end): (u32, u32)) -> String {
+    if start == end {
+        format!("{}{}", prefix, start)
+    } else {
+        format!("{}{}-{}", prefix, start, end)
+    }
+}
+
+/// Format groups as the shortest list that parses back to the same groups, e.g. `3, 7-10` for `3 7-9, 10`.
+///
+/// Numbers come first, then labelled ranges like `A1-3`, then any other labels.
+/// Exclusions and open ranges are never used, so no groups is an empty string.
+pub fn format_groups(groups: &GroupSet) -> String {
+    let mut prefixed: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+    let mut labels = Vec::new();
+    for label in groups.labels() {
+        match split_label(label) {
+            Some((prefix, number)) => {
+                prefixed.entry(prefix).or_default().insert(number);
+            }
+            None => labels.push(label),
         }
     }
-    items.sort();
-    items.dedup();
-    items
+
+    let mut parts: Vec<String> = groups.ranges().map(|run| format_run("", run)).collect();
+    for (prefix, numbers) in prefixed {
+        parts.extend(runs(numbers).into_iter().map(|run| format_run(prefix, run)));
+    }
+    parts.extend(labels.into_iter().map(str::to_owned));
+    parts.join(", ")
+}
+
+impl fmt::Display for GroupSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_groups(self))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn numbers(numbers: &[u32]) -> GroupSet {
+        numbers.iter().cloned().map(Group::Number).collect()
+    }
+
     #[test]
     fn test_parse_group_range() {
-        let all_groups: Vec<u32> = (200..300).collect();
+        let universe = GroupUniverse::default();
+        let all_groups = universe.groups();
 
         // Single item
-        assert_eq!(parse_group_range("0"), vec![0]);
+        assert_eq!(parse_group_range("0", &universe), numbers(&[0]));
         // Range of items, inclusive
-        assert_eq!(parse_group_range("0-2"), vec![0, 1, 2]);
+        assert_eq!(parse_group_range("0-2", &universe), numbers(&[0, 1, 2]));
         // Multiple spec, separted by comma
-        assert_eq!(parse_group_range("0, 7"), vec![0, 7]);
-        assert_eq!(parse_group_range("0, 7-10"), vec![0, 7, 8, 9, 10]);
+        assert_eq!(parse_group_range("0, 7", &universe), numbers(&[0, 7]));
+        assert_eq!(
+            parse_group_range("0, 7-10", &universe),
+            numbers(&[0, 7, 8, 9, 10])
+        );
 
         // When in doubt, default to everyone
-        assert_eq!(parse_group_range("0, 297-spam"), all_groups);
-        assert_eq!(parse_group_range("0, spam-201"), all_groups);
-        assert_eq!(parse_group_range(""), all_groups);
-        assert_eq!(parse_group_range("250, spam"), all_groups);
+        assert_eq!(parse_group_range("0, 297-spam", &universe), all_groups);
+        assert_eq!(parse_group_range("0, spam-201", &universe), all_groups);
+        assert_eq!(parse_group_range("", &universe), all_groups);
+        assert_eq!(parse_group_range("250, spam", &universe), all_groups);
 
         // Weird and wonderful edge case
         assert_eq!(
-            parse_group_range("121,123 - 125   , 121"),
-            vec![121, 123, 124, 125],
+            parse_group_range("121,123 - 125   , 121", &universe),
+            numbers(&[121, 123, 124, 125]),
         );
         // Trailing whitespace is bad, make sure to trim it
-        assert_eq!(parse_group_range("261 "), vec![261]);
+        assert_eq!(parse_group_range("261 ", &universe), numbers(&[261]));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let universe = GroupUniverse::default();
+        assert_eq!(parse_groups("0, 7-8", &universe), Ok(numbers(&[0, 7, 8])));
+        assert_eq!(parse_groups(" 261 ", &universe), Ok(numbers(&[261])));
+
+        // Errors point at the unexpected token
+        assert_eq!(
+            parse_groups("0, 297-spam", &universe),
+            Err(GroupParseError {
+                offset: 7,
+                token: "spam".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_groups("250, spam", &universe),
+            Err(GroupParseError {
+                offset: 5,
+                token: "spam".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_groups("  spam", &universe),
+            Err(GroupParseError {
+                offset: 2,
+                token: "spam".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_groups(" ", &universe),
+            Err(GroupParseError {
+                offset: 1,
+                token: "".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_groups("250, spam", &universe)
+                .unwrap_err()
+                .to_string(),
+            "unexpected 'spam' at byte 5"
+        );
+    }
+
+    #[test]
+    fn test_parse_groups_bad_ranges() {
+        let universe = GroupUniverse::default();
+        assert_eq!(
+            parse_groups("A1-A1000", &universe).map(|groups| groups.iter().count()),
+            Ok(MAX_LABEL_RANGE as usize)
+        );
+        // Too long to list, but found quickly
+        for input in &["A1-A20000000", "A0-A4294967295"] {
+            assert_eq!(
+                parse_groups(input, &universe),
+                Err(GroupParseError::at(input, input))
+            );
+        }
+        assert_eq!(
+            parse_groups("250, 5-3", &universe).unwrap_err().to_string(),
+            "unexpected '5-3' at byte 5"
+        );
+        assert_eq!(
+            parse_groups("300-", &universe).unwrap_err().to_string(),
+            "unexpected '300-' at byte 0"
+        );
+        // An open range from the last group is just that group
+        assert_eq!(parse_groups("299-", &universe), Ok(numbers(&[299])));
+    }
+
+    #[test]
+    fn test_parse_universe() {
+        assert_eq!("200-299".parse(), Ok(GroupUniverse::default()));
+        assert_eq!(" 1 - 4 ".parse(), Ok(GroupUniverse { start: 1, end: 4 }));
+        assert_eq!(
+            "200".parse::<GroupUniverse>().unwrap_err().to_string(),
+            "unexpected end of input at byte 3"
+        );
+        assert_eq!(
+            "200-A".parse::<GroupUniverse>().unwrap_err().to_string(),
+            "unexpected 'A' at byte 4"
+        );
+        assert_eq!(
+            "299-200".parse::<GroupUniverse>().unwrap_err().to_string(),
+            "unexpected '200' at byte 4"
+        );
+    }
+
+    #[test]
+    fn test_parse_group_range_universe() {
+        let universe = GroupUniverse { start: 1, end: 4 };
+
+        // Explicit groups may be outside the universe
+        assert_eq!(parse_group_range("7", &universe), numbers(&[7]));
+
+        // Anything else is the whole universe
+        assert_eq!(parse_group_range("", &universe), numbers(&[1, 2, 3, 4]));
+        assert_eq!(parse_group_range("spam", &universe), numbers(&[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_parse_groups_extended() {
+        let universe = GroupUniverse::default();
+
+        // Exclusions
+        assert_eq!(
+            parse_groups("201-205 except 203", &universe),
+            Ok(numbers(&[201, 202, 204, 205]))
+        );
+        assert_eq!(
+            parse_groups("201-205, 207 EXCEPT 203-204, 207", &universe),
+            Ok(numbers(&[201, 202, 205]))
+        );
+
+        // Labelled groups
+        assert_eq!(
+            parse_groups("253a, 201", &universe),
+            Ok(vec![Group::Number(201), Group::Label("253a".to_owned())]
+                .into_iter()
+                .collect())
+        );
+        assert_eq!(
+            parse_groups("A1-A3, B2-4", &universe),
+            Ok(["A1", "A2", "A3", "B2", "B3", "B4"]
+                .iter()
+                .cloned()
+                .map(Group::from)
+                .collect())
+        );
+        // Labels in a range must share a prefix
+        assert_eq!(
+            parse_groups("A1-B3", &universe),
+            Err(GroupParseError {
+                offset: 2,
+                token: "-B3".to_owned()
+            })
+        );
+
+        // Open ranges end with the universe
+        assert_eq!(
+            parse_groups("297-", &universe),
+            Ok(numbers(&[297, 298, 299]))
+        );
+        assert_eq!(
+            parse_groups("295- except 297", &universe),
+            Ok(numbers(&[295, 296, 298, 299]))
+        );
+    }
+
+    #[test]
+    fn test_format_groups() {
+        assert_eq!(format_groups(&numbers(&[3, 7, 8, 9, 10])), "3, 7-10");
+        assert_eq!(format_groups(&numbers(&[10, 3, 3, 7, 8])), "3, 7-8, 10");
+        assert_eq!(format_groups(&GroupSet::new()), "");
+        assert_eq!(
+            format_groups(&numbers(&[4294967294, 4294967295, 0])),
+            "0, 4294967294-4294967295"
+        );
+
+        let labels: GroupSet = ["A1", "A2", "A3", "A10", "B2", "253a", "A01", "201"]
+            .iter()
+            .cloned()
+            .map(Group::from)
+            .collect();
+        assert_eq!(format_groups(&labels), "201, A1-3, A10, B2, 253a, A01");
+    }
+
+    #[test]
+    fn test_group_from_str() {
+        assert_eq!(Group::from("253"), Group::Number(253));
+        assert_eq!(Group::from("253a"), Group::Label("253a".to_owned()));
+        assert_eq!(
+            serde_json::from_str::<Vec<Group>>(r#"[253, "254", "A1"]"#).unwrap(),
+            vec![
+                Group::Number(253),
+                Group::Number(254),
+                Group::Label("A1".to_owned())
+            ]
+        );
     }
 }
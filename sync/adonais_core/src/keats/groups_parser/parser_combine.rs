@@ -0,0 +1,239 @@
+use combine::{
+    attempt,
+    error::StringStreamError,
+    many, many1, optional,
+    parser::char::{char, digit, string_cmp},
+    satisfy, skip_many, skip_many1, Parser,
+};
+
+use super::{GroupParseError, Part, Range};
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
+}
+
+fn multispace0<'a>() -> impl Parser<&'a str, Output = ()> {
+    skip_many(satisfy(is_space))
+}
+
+fn multispace1<'a>() -> impl Parser<&'a str, Output = ()> {
+    skip_many1(satisfy(is_space))
+}
+
+fn letter<'a>() -> impl Parser<&'a str, Output = char> {
+    satisfy(|c: char| c.is_ascii_alphabetic())
+}
+
+fn dash_delimiter<'a>() -> impl Parser<&'a str, Output = char> {
+    (multispace0(), char('-'), multispace0()).map(|(_, dash, _)| dash)
+}
+
+fn comma_or_space_delimiter<'a>() -> impl Parser<&'a str, Output = char> {
+    attempt((multispace0(), char(','), multispace0()).map(|(_, comma, _)| comma)).or(char(' '))
+}
+
+fn single<'a>() -> impl Parser<&'a str, Output = u32> {
+    many1(digit()).and_then(|digits: String| {
+        digits
+            .parse()
+            .map_err(|_| StringStreamError::UnexpectedParse)
+    })
+}
+
+fn range<'a>() -> impl Parser<&'a str, Output = Range> {
+    (single(), dash_delimiter(), single()).map(|(start, _, end)| Range { start, end })
+}
+
+fn prefix<'a>() -> impl Parser<&'a str, Output = String> {
+    many1(letter())
+}
+
+fn label_range<'a>() -> impl Parser<&'a str, Output = Part> {
+    (
+        prefix(),
+        single(),
+        dash_delimiter(),
+        optional(prefix()),
+        single(),
+    )
+        .and_then(|(prefix, start, _, end_prefix, end)| match end_prefix {
+            Some(end_prefix) if end_prefix != prefix => Err(StringStreamError::UnexpectedParse),
+            _ => Ok(Part::LabelRange {
+                prefix,
+                range: Range { start, end },
+            }),
+        })
+}
+
+fn open_range<'a>() -> impl Parser<&'a str, Output = u32> {
+    (single(), multispace0(), char('-')).map(|(start, _, _)| start)
+}
+
+fn label<'a>() -> impl Parser<&'a str, Output = String> {
+    let letters_first = (many1(letter()), many1(digit()), many(letter()))
+        .map(|(letters, digits, suffix): (String, String, String)| letters + &digits + &suffix);
+    let digits_first = (many1(digit()), many1(letter()))
+        .map(|(digits, letters): (String, String)| digits + &letters);
+    attempt(letters_first).or(digits_first)
+}
+
+fn part<'a>() -> impl Parser<&'a str, Output = Part> {
+    attempt(range().map(Part::Range))
+        .or(attempt(label_range()))
+        .or(attempt(open_range().map(Part::OpenRange)))
+        .or(attempt(label().map(Part::Label)))
+        .or(single().map(Part::Single))
+}
+
+fn list<'a>() -> impl Parser<&'a str, Output = Vec<Part>> {
+    // Only consume a delimiter if a part follows it
+    (
+        part(),
+        many(attempt(comma_or_space_delimiter().with(part()))),
+    )
+        .map(|(first, rest): (Part, Vec<Part>)| {
+            let mut parts = vec![first];
+            parts.extend(rest);
+            parts
+        })
+}
+
+fn exclusions<'a>() -> impl Parser<&'a str, Output = Vec<Part>> {
+    (
+        multispace1(),
+        string_cmp("except", |l: char, r: char| l.eq_ignore_ascii_case(&r)),
+        multispace1(),
+    )
+        .with(list())
+}
+
+fn groups<'a>() -> impl Parser<&'a str, Output = Vec<Part>> {
+    (list(), optional(attempt(exclusions()))).map(|(mut parts, excluded)| {
+        parts.extend(
+            excluded
+                .into_iter()
+                .flatten()
+                .map(|part| Part::Exclude(Box::new(part))),
+        );
+        parts
+    })
+}
+
+/// Parse the whole input, or return an error at the first unexpected token.
+pub fn tree(input: &str) -> Result<Vec<Part>, GroupParseError> {
+    match groups().parse(input) {
+        Ok((parts, "")) => Ok(parts),
+        Ok((_, leftover)) => Err(GroupParseError::leftover(input, leftover)),
+        // Only the first part can fail without backtracking
+        Err(_) => Err(GroupParseError::at(input, input)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    fn assert_eq_and_complete<T: Debug + PartialEq, E: Debug + PartialEq>(
+        actual: Result<(T, &str), E>,
+        expected: T,
+    ) {
+        assert_eq!(actual, Ok((expected, "")))
+    }
+
+    #[test]
+    fn parse_single() {
+        assert_eq_and_complete(single().parse("123"), 123);
+    }
+
+    #[test]
+    fn parse_range() {
+        assert_eq_and_complete(
+            range().parse("123 - 125"),
+            Range {
+                start: 123,
+                end: 125,
+            },
+        );
+    }
+
+    #[test]
+    fn parse_part() {
+        assert_eq_and_complete(
+            part().parse("123 - 125"),
+            Part::Range(Range {
+                start: 123,
+                end: 125,
+            }),
+        );
+        assert_eq_and_complete(part().parse("121"), Part::Single(121));
+        assert_eq_and_complete(part().parse("121-"), Part::OpenRange(121));
+        assert_eq_and_complete(part().parse("121a"), Part::Label("121a".to_owned()));
+        assert_eq_and_complete(
+            part().parse("A1 - A4"),
+            Part::LabelRange {
+                prefix: "A".to_owned(),
+                range: Range { start: 1, end: 4 },
+            },
+        );
+    }
+
+    #[test]
+    fn parse_groups() {
+        // delimiter includes comma
+        assert_eq_and_complete(
+            list().parse("121,123 - 125   , 121"),
+            vec![
+                Part::Single(121),
+                Part::Range(Range {
+                    start: 123,
+                    end: 125,
+                }),
+                Part::Single(121),
+            ],
+        );
+
+        // delimiter does not include comma
+        assert_eq_and_complete(
+            list().parse("121 123 - 125 121"),
+            vec![
+                Part::Single(121),
+                Part::Range(Range {
+                    start: 123,
+                    end: 125,
+                }),
+                Part::Single(121),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_exclusions() {
+        assert_eq_and_complete(
+            groups().parse("1-3 except 2"),
+            vec![
+                Part::Range(Range { start: 1, end: 3 }),
+                Part::Exclude(Box::new(Part::Single(2))),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_tree() {
+        assert_eq!(
+            tree("250, spam"),
+            Err(GroupParseError {
+                offset: 5,
+                token: "spam".to_owned()
+            })
+        );
+        assert_eq!(
+            tree("spam"),
+            Err(GroupParseError {
+                offset: 0,
+                token: "spam".to_owned()
+            })
+        );
+    }
+}
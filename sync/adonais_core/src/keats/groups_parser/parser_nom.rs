@@ -1,21 +1,25 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
-    character::complete::multispace0,
-    combinator::{map, map_res},
+    bytes::complete::{tag, tag_no_case, take_while, take_while1},
+    character::complete::{multispace0, multispace1},
+    combinator::{map, map_res, opt, recognize},
     multi::separated_nonempty_list,
-    sequence::{delimited, tuple},
-    IResult,
+    sequence::{delimited, preceded, terminated, tuple},
+    Err, IResult,
 };
 
-use super::{Part, Range};
+use super::{GroupParseError, Part, Range};
 
 fn to_u32(input: &str) -> Result<u32, std::num::ParseIntError> {
     input.parse()
 }
 
 fn is_digit(c: char) -> bool {
-    c.is_digit(10)
+    c.is_ascii_digit()
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_ascii_alphabetic()
 }
 
 fn dash_delimiter(input: &str) -> IResult<&str, &str> {
@@ -35,10 +39,45 @@ fn range(input: &str) -> IResult<&str, Range> {
     Ok((input, Range { start, end }))
 }
 
+fn prefix(input: &str) -> IResult<&str, &str> {
+    take_while1(is_letter)(input)
+}
+
+fn label_range(input: &str) -> IResult<&str, Part> {
+    map_res(
+        tuple((prefix, single, dash_delimiter, opt(prefix), single)),
+        |(prefix, start, _, end_prefix, end)| match end_prefix {
+            Some(end_prefix) if end_prefix != prefix => Err("Label prefixes differ."),
+            _ => Ok(Part::LabelRange {
+                prefix: prefix.to_owned(),
+                range: Range { start, end },
+            }),
+        },
+    )(input)
+}
+
+fn open_range(input: &str) -> IResult<&str, u32> {
+    terminated(single, tuple((multispace0, tag("-"))))(input)
+}
+
+fn label(input: &str) -> IResult<&str, &str> {
+    alt((
+        recognize(tuple((
+            take_while1(is_letter),
+            take_while1(is_digit),
+            take_while(is_letter),
+        ))),
+        recognize(tuple((take_while1(is_digit), take_while1(is_letter)))),
+    ))(input)
+}
+
 fn part(input: &str) -> IResult<&str, Part> {
     alt((
-        map(range, |r| Part::Range(r)),
-        map(single, |s| Part::Single(s)),
+        map(range, Part::Range),
+        label_range,
+        map(open_range, Part::OpenRange),
+        map(label, |label| Part::Label(label.to_owned())),
+        map(single, Part::Single),
     ))(input)
 }
 
@@ -46,21 +85,34 @@ fn list(input: &str) -> IResult<&str, Vec<Part>> {
     separated_nonempty_list(comma_or_space_delimiter, part)(input)
 }
 
-pub fn tree(input: &str) -> Vec<Part> {
-    let (leftover, mut parts) = list(input.trim()).unwrap_or((
-        "",
-        vec![Part::Range(Range {
-            start: 200,
-            end: 299,
-        })],
-    ));
-    if leftover.len() > 0 {
-        parts = vec![Part::Range(Range {
-            start: 200,
-            end: 299,
-        })];
-    };
-    parts
+fn exclusions(input: &str) -> IResult<&str, Vec<Part>> {
+    preceded(
+        tuple((multispace1, tag_no_case("except"), multispace1)),
+        list,
+    )(input)
+}
+
+fn groups(input: &str) -> IResult<&str, Vec<Part>> {
+    let (input, (mut parts, excluded)) = tuple((list, opt(exclusions)))(input)?;
+    parts.extend(
+        excluded
+            .into_iter()
+            .flatten()
+            .map(|part| Part::Exclude(Box::new(part))),
+    );
+    Ok((input, parts))
+}
+
+/// Parse the whole input, or return an error at the first unexpected token.
+pub fn tree(input: &str) -> Result<Vec<Part>, GroupParseError> {
+    match groups(input) {
+        Ok(("", parts)) => Ok(parts),
+        Ok((leftover, _)) => Err(GroupParseError::leftover(input, leftover)),
+        Err(Err::Error((rest, _))) | Err(Err::Failure((rest, _))) => {
+            Err(GroupParseError::at(input, rest))
+        }
+        Err(Err::Incomplete(_)) => Err(GroupParseError::at(input, "")),
+    }
 }
 
 #[cfg(test)]
@@ -102,6 +154,15 @@ mod tests {
             }),
         );
         assert_eq_and_complete(part("121"), Part::Single(121));
+        assert_eq_and_complete(part("121-"), Part::OpenRange(121));
+        assert_eq_and_complete(part("121a"), Part::Label("121a".to_owned()));
+        assert_eq_and_complete(
+            part("A1 - A4"),
+            Part::LabelRange {
+                prefix: "A".to_owned(),
+                range: Range { start: 1, end: 4 },
+            },
+        );
     }
 
     #[test]
@@ -132,4 +193,15 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn parse_exclusions() {
+        assert_eq_and_complete(
+            groups("1-3 except 2"),
+            vec![
+                Part::Range(Range { start: 1, end: 3 }),
+                Part::Exclude(Box::new(Part::Single(2))),
+            ],
+        );
+    }
 }
@@ -1,38 +1,105 @@
+use pest::iterators::Pair;
 use pest::Parser;
 
 #[derive(Parser)]
 #[grammar = "keats/groups_parser/groups.pest"]
 struct GroupsParser;
 
-use super::{Part, Range};
+use super::{GroupParseError, Part, Range};
 
-pub fn tree(input: &str) -> Vec<Part> {
-    let pair = GroupsParser::parse(Rule::list, input)
-        .unwrap()
-        .next()
-        .unwrap();
+/// Parse the whole input, or return an error at the first unexpected token.
+pub fn tree(input: &str) -> Result<Vec<Part>, GroupParseError> {
+    let pair = match GroupsParser::parse(Rule::groups, input) {
+        Ok(mut pairs) => pairs.next().expect("Groups rule produces a pair."),
+        // Everything after the first part is optional, so that is what failed
+        Err(_) => return Err(GroupParseError::at(input, input)),
+    };
 
     // if we didn't match the entire pattern in one list
-    if (pair.as_span().end() - pair.as_span().start()) != input.len() {
-        return vec![Part::Range(Range {
-            start: 200,
-            end: 299,
-        })];
+    let end = pair.as_span().end();
+    if end != input.len() {
+        return Err(GroupParseError::leftover(input, &input[end..]));
     }
 
-    let parts = pair
-        .into_inner()
-        .map(|pair| match pair.as_rule() {
-            Rule::range => {
-                let mut inner_rules = pair.into_inner();
-                let start = inner_rules.next().unwrap().as_str().parse().unwrap();
-                let end = inner_rules.next().unwrap().as_str().parse().unwrap();
-                Part::Range(Range { start, end })
+    let mut inner_rules = pair.into_inner();
+    let list = inner_rules.next().expect("Groups have a list.");
+    let mut parts = parse_list(input, list, 0)?;
+    if let Some(exclusions) = inner_rules.next() {
+        let mut inner_rules = exclusions.into_inner();
+        let except = inner_rules.next().expect("Exclusions start with except.");
+        let list = inner_rules.next().expect("Exclusions have a list.");
+        // An invalid first part means there are no exclusions, so the error is at except
+        let excluded = parse_list(input, list, except.as_span().start())?;
+        parts.extend(
+            excluded
+                .into_iter()
+                .map(|part| Part::Exclude(Box::new(part))),
+        );
+    }
+    Ok(parts)
+}
+
+/// Convert a list into parts, with errors where the other backends would stop.
+///
+/// If the first part cannot be parsed at all, the error is at `first_offset`.
+fn parse_list(
+    input: &str,
+    list: Pair<Rule>,
+    first_offset: usize,
+) -> Result<Vec<Part>, GroupParseError> {
+    let to_u32 = |pair: &Pair<Rule>, error_offset: usize| {
+        pair.as_str()
+            .parse()
+            .map_err(|_| GroupParseError::at(input, &input[error_offset..]))
+    };
+    list.into_inner()
+        .enumerate()
+        .map(|(index, pair)| {
+            let part_offset = if index == 0 {
+                first_offset
+            } else {
+                pair.as_span().start()
+            };
+            match pair.as_rule() {
+                Rule::range => {
+                    let mut inner_rules = pair.into_inner();
+                    let start = inner_rules.next().expect("Range has a start.");
+                    let end = inner_rules.next().expect("Range has an end.");
+                    // An invalid end means this is an open range, so the error is at the end
+                    Ok(Part::Range(Range {
+                        start: to_u32(&start, part_offset)?,
+                        end: to_u32(&end, end.as_span().start())?,
+                    }))
+                }
+                Rule::label_range => {
+                    let mut inner_rules = pair.into_inner();
+                    let prefix = inner_rules.next().expect("Label range has a prefix.");
+                    let start = inner_rules.next().expect("Label range has a start.");
+                    let mut end = inner_rules.next().expect("Label range has an end.");
+                    // Anything invalid means this is a label, so the error is after the start
+                    let error_offset = start.as_span().end();
+                    if end.as_rule() == Rule::prefix {
+                        if end.as_str() != prefix.as_str() {
+                            return Err(GroupParseError::at(input, &input[error_offset..]));
+                        }
+                        end = inner_rules.next().expect("Label range has an end.");
+                    }
+                    Ok(Part::LabelRange {
+                        prefix: prefix.as_str().to_owned(),
+                        range: Range {
+                            start: to_u32(&start, error_offset)?,
+                            end: to_u32(&end, error_offset)?,
+                        },
+                    })
+                }
+                Rule::open_range => {
+                    let start = pair.into_inner().next().expect("Open range has a start.");
+                    Ok(Part::OpenRange(to_u32(&start, part_offset)?))
+                }
+                Rule::label => Ok(Part::Label(pair.as_str().to_owned())),
+                Rule::single => Ok(Part::Single(to_u32(&pair, part_offset)?)),
+                _ => unreachable!(),
             }
-            Rule::single => Part::Single(pair.as_str().parse().unwrap()),
-            Rule::list | Rule::WHITESPACE => unreachable!(),
         })
-        .collect();
-
-    parts
+        .collect()
 }
@@ -1,27 +1,29 @@
+pub mod client;
 pub mod groups_parser;
+pub mod snapshot;
 
 /// An event as returned from the KEATS API.
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct Event {
-    #[serde(rename(deserialize = "C"))]
+    #[serde(rename = "C")]
     pub code: String,
-    #[serde(rename(deserialize = "Date"))]
+    #[serde(rename = "Date")]
     pub date: String,
-    #[serde(rename(deserialize = "N"))]
+    #[serde(rename = "N")]
     pub title: Option<String>,
-    #[serde(rename(deserialize = "T"))]
+    #[serde(rename = "T")]
     pub type_: Option<String>,
-    #[serde(rename(deserialize = "ST"))]
+    #[serde(rename = "ST")]
     pub start_time: String,
-    #[serde(rename(deserialize = "ET"))]
+    #[serde(rename = "ET")]
     pub end_time: String,
-    #[serde(rename(deserialize = "G"))]
+    #[serde(rename = "G")]
     pub groups: Option<String>,
-    #[serde(rename(deserialize = "S"))]
+    #[serde(rename = "S")]
     pub staff: Option<String>,
-    #[serde(rename(deserialize = "R"))]
+    #[serde(rename = "R")]
     pub room: Option<String>,
-    #[serde(rename(deserialize = "CP"))]
+    #[serde(rename = "CP")]
     pub campus: Option<String>,
 }
 
@@ -0,0 +1,577 @@
+//! Keeping the last good KEATS response for each module.
+//!
+//! Snapshots are revalidated with `ETag` or `Last-Modified` where KEATS sends them, and by a hash of the body otherwise,
+//! so unchanged timetables can be spotted. When KEATS is down, the last good snapshot is used instead.
+//! Snapshots are plain JSON records keyed by module, so they can be kept in files or in IndexedDB alike.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use super::client::{check_status, decode, Error, ModuleId};
+use super::Event;
+
+/// A good KEATS response for a module.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub module: ModuleId,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// A hash of the body, to spot unchanged timetables when KEATS sends no validators.
+    pub hash: String,
+    /// When KEATS last confirmed this snapshot.
+    pub fetched: DateTime<Utc>,
+    /// The body as sent by KEATS.
+    pub body: String,
+}
+
+impl Snapshot {
+    /// The headers asking KEATS to respond with `304 Not Modified` if the timetable is unchanged.
+    pub fn conditional_headers(&self) -> Vec<(&'static str, &str)> {
+        let mut headers = vec![];
+        headers.extend(self.etag.as_deref().map(|etag| ("If-None-Match", etag)));
+        headers.extend(
+            self.last_modified
+                .as_deref()
+                .map(|last_modified| ("If-Modified-Since", last_modified)),
+        );
+        headers
+    }
+}
+
+/// A response from KEATS, however it was fetched.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Response {
+    pub status: u16,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    pub body: String,
+}
+
+/// How a snapshot compares to the one before it.
+#[derive(Debug)]
+pub enum Freshness {
+    /// The timetable is new, or has changed.
+    Changed,
+    /// KEATS confirmed the timetable is unchanged, so any work done with it need not be repeated.
+    Unchanged,
+    /// KEATS could not be used, so this is the last good snapshot.
+    Stale(Error),
+}
+
+/// The events of a module, and the snapshot they came from.
+#[derive(Debug)]
+pub struct Fetched {
+    pub snapshot: Snapshot,
+    pub events: Vec<Event>,
+    pub freshness: Freshness,
+}
+
+/// Work out the latest snapshot from the `previous` one and a response from `url`, fetched at `now`.
+///
+/// Only valid timetables replace the previous snapshot. If there is no previous snapshot to fall back on, errors are returned.
+pub fn revalidate(
+    module: &ModuleId,
+    url: &str,
+    previous: Option<Snapshot>,
+    response: Result<Response, Error>,
+    now: DateTime<Utc>,
+) -> Result<Fetched, Error> {
+    let latest = response.and_then(|response| {
+        if let (304, Some(previous)) = (response.status, &previous) {
+            return Ok(Snapshot {
+                etag: response.etag.or_else(|| previous.etag.clone()),
+                last_modified: response
+                    .last_modified
+                    .or_else(|| previous.last_modified.clone()),
+                fetched: now,
+                ..previous.clone()
+            });
+        }
+        let body = check_status(url, response.status, response.body)?;
+        Ok(Snapshot {
+            module: module.clone(),
+            etag: response.etag,
+            last_modified: response.last_modified,
+            hash: crate::hash_id(&body),
+            fetched: now,
+            body,
+        })
+    });
+    let (snapshot, events) = match latest.and_then(|latest| Ok((decode(&latest.body)?, latest))) {
+        Ok((events, latest)) => (latest, events),
+        Err(error) => {
+            let previous = match previous {
+                Some(previous) => previous,
+                None => return Err(error),
+            };
+            let events = decode(&previous.body)?;
+            return Ok(Fetched {
+                snapshot: previous,
+                events,
+                freshness: Freshness::Stale(error),
+            });
+        }
+    };
+    let freshness = match previous {
+        Some(previous) if previous.hash == snapshot.hash => Freshness::Unchanged,
+        _ => Freshness::Changed,
+    };
+    Ok(Fetched {
+        snapshot,
+        events,
+        freshness,
+    })
+}
+
+/// A request to revalidate a snapshot with a response fetched elsewhere, such as in the browser.
+#[derive(Debug, Deserialize)]
+pub struct RevalidateRequest {
+    pub module: ModuleId,
+    /// Where the response was fetched from, to explain errors.
+    pub url: String,
+    #[serde(default)]
+    pub previous: Option<Snapshot>,
+    /// The response, if one was received.
+    #[serde(default)]
+    pub response: Option<Response>,
+    /// Why no response was received.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreshnessName {
+    Changed,
+    Unchanged,
+    Stale,
+}
+
+/// A snapshot without its body, which the caller already has as the response or the previous snapshot.
+///
+/// The body can be large, and is already decoded as `events`, so is not sent back.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SnapshotInfo {
+    pub module: ModuleId,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub hash: String,
+    pub fetched: DateTime<Utc>,
+}
+
+impl SnapshotInfo {
+    /// The snapshot to store, with the body of the response if changed, or of the previous snapshot if not.
+    pub fn with_body(self, body: String) -> Snapshot {
+        Snapshot {
+            module: self.module,
+            etag: self.etag,
+            last_modified: self.last_modified,
+            hash: self.hash,
+            fetched: self.fetched,
+            body,
+        }
+    }
+}
+
+impl From<Snapshot> for SnapshotInfo {
+    fn from(snapshot: Snapshot) -> Self {
+        SnapshotInfo {
+            module: snapshot.module,
+            etag: snapshot.etag,
+            last_modified: snapshot.last_modified,
+            hash: snapshot.hash,
+            fetched: snapshot.fetched,
+        }
+    }
+}
+
+/// The latest snapshot, to store for next time unless it is stale.
+#[derive(Debug, Serialize)]
+pub struct RevalidateResponse {
+    pub snapshot: SnapshotInfo,
+    pub events: Vec<Event>,
+    pub freshness: FreshnessName,
+    /// Why KEATS could not be used, if the snapshot is stale.
+    pub error: Option<String>,
+}
+
+/// Revalidate a snapshot from a request, at `now`.
+pub fn revalidate_request(
+    request: RevalidateRequest,
+    now: DateTime<Utc>,
+) -> Result<RevalidateResponse, Error> {
+    let RevalidateRequest {
+        module,
+        url,
+        previous,
+        response,
+        error,
+    } = request;
+    let response = response
+        .ok_or_else(|| Error::Unavailable(error.unwrap_or_else(|| "no response".to_owned())));
+    let fetched = revalidate(&module, &url, previous, response, now)?;
+    let (freshness, error) = match fetched.freshness {
+        Freshness::Changed => (FreshnessName::Changed, None),
+        Freshness::Unchanged => (FreshnessName::Unchanged, None),
+        Freshness::Stale(error) => (FreshnessName::Stale, Some(error.to_string())),
+    };
+    Ok(RevalidateResponse {
+        snapshot: fetched.snapshot.into(),
+        events: fetched.events,
+        freshness,
+        error,
+    })
+}
+
+/// Somewhere to keep snapshots between syncs.
+pub trait Store {
+    fn load(&self, module: &ModuleId) -> io::Result<Option<Snapshot>>;
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()>;
+}
+
+/// Snapshots kept as `<module>.json` files in a directory.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    directory: PathBuf,
+}
+
+impl FileStore {
+    /// A store in `directory`, which is created when the first snapshot is saved.
+    pub fn new(directory: PathBuf) -> Self {
+        FileStore { directory }
+    }
+
+    fn path(&self, module: &ModuleId) -> PathBuf {
+        self.directory.join(format!("{}.json", module))
+    }
+}
+
+impl Store for FileStore {
+    fn load(&self, module: &ModuleId) -> io::Result<Option<Snapshot>> {
+        match fs::read_to_string(self.path(module)) {
+            Ok(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        // Write then rename, so a failed write never loses the last good snapshot
+        let path = self.path(&snapshot.module);
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_string(snapshot)?)?;
+        fs::rename(partial, path)
+    }
+}
+
+#[cfg(feature = "client")]
+impl super::client::Client {
+    /// Fetch a module's timetable, revalidating the snapshot in `store` and saving the latest.
+    pub fn fetch_snapshot(
+        &self,
+        module: &ModuleId,
+        store: &dyn Store,
+        now: DateTime<Utc>,
+    ) -> Result<Fetched, Error> {
+        let previous = store.load(module).map_err(Error::Store)?;
+        let url = self.url(module);
+        let mut request = reqwest::blocking::Client::new().get(&url);
+        for (name, value) in previous.iter().flat_map(Snapshot::conditional_headers) {
+            request = request.header(name, value);
+        }
+        let response = request.send().and_then(|response| {
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned)
+            };
+            Ok(Response {
+                status: response.status().as_u16(),
+                etag: header(reqwest::header::ETAG),
+                last_modified: header(reqwest::header::LAST_MODIFIED),
+                body: response.text()?,
+            })
+        });
+        let fetched = revalidate(module, &url, previous, response.map_err(Error::Http), now)?;
+        if !matches!(fetched.freshness, Freshness::Stale(_)) {
+            store.save(&fetched.snapshot).map_err(Error::Store)?;
+        }
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    const EVENTS: &str = r#"[{"C": "5MBBS201-OW", "Date": "2019-09-09T00:00:00", "N": null, "T": null,
+        "ST": "09:00", "ET": "12:30", "G": "201-289", "S": null, "R": null, "CP": null}]"#;
+
+    const URL: &str = "http://keats/values/Mod-Module.5MBBSStage2";
+
+    fn now(day: u32) -> DateTime<Utc> {
+        Utc.ymd(2019, 9, day).and_hms(0, 0, 0)
+    }
+
+    fn ok(body: &str, etag: Option<&str>) -> Result<Response, Error> {
+        Ok(Response {
+            status: 200,
+            etag: etag.map(str::to_owned),
+            last_modified: None,
+            body: body.to_owned(),
+        })
+    }
+
+    fn first() -> Snapshot {
+        let fetched = revalidate(
+            &ModuleId::default(),
+            URL,
+            None,
+            ok(EVENTS, Some("\"v1\"")),
+            now(1),
+        )
+        .unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Changed));
+        assert_eq!(fetched.events.len(), 1);
+        fetched.snapshot
+    }
+
+    #[test]
+    fn test_revalidate_unchanged() {
+        let first = first();
+        assert_eq!(
+            first.conditional_headers(),
+            vec![("If-None-Match", "\"v1\"")]
+        );
+
+        // Confirmed by the server
+        let not_modified = Ok(Response {
+            status: 304,
+            etag: None,
+            last_modified: Some("Mon, 02 Sep 2019 00:00:00 GMT".to_owned()),
+            body: String::new(),
+        });
+        let fetched = revalidate(
+            &first.module,
+            URL,
+            Some(first.clone()),
+            not_modified,
+            now(2),
+        )
+        .unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Unchanged));
+        assert_eq!(fetched.events.len(), 1);
+        assert_eq!(fetched.snapshot.fetched, now(2));
+        assert_eq!(fetched.snapshot.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(
+            fetched.snapshot.conditional_headers(),
+            vec![
+                ("If-None-Match", "\"v1\""),
+                ("If-Modified-Since", "Mon, 02 Sep 2019 00:00:00 GMT")
+            ]
+        );
+
+        // Without validators, by the content hash
+        let fetched = revalidate(
+            &first.module,
+            URL,
+            Some(first.clone()),
+            ok(EVENTS, None),
+            now(2),
+        )
+        .unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Unchanged));
+        assert_eq!(fetched.snapshot.hash, first.hash);
+    }
+
+    #[test]
+    fn test_revalidate_changed() {
+        let first = first();
+        let moved = EVENTS.replace("09:00", "10:00");
+        let fetched = revalidate(
+            &first.module,
+            URL,
+            Some(first.clone()),
+            ok(&moved, None),
+            now(2),
+        )
+        .unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Changed));
+        assert_eq!(fetched.events[0].start_time, "10:00");
+        assert_ne!(fetched.snapshot.hash, first.hash);
+        assert_eq!(fetched.snapshot.etag, None);
+    }
+
+    #[test]
+    fn test_revalidate_stale() {
+        let first = first();
+        let failures = vec![
+            ok("<html>Service unavailable</html>", None),
+            Ok(Response {
+                status: 503,
+                etag: None,
+                last_modified: None,
+                body: "Down".to_owned(),
+            }),
+            Err(Error::InvalidModule("offline".to_owned())),
+        ];
+        for failure in failures {
+            let fetched =
+                revalidate(&first.module, URL, Some(first.clone()), failure, now(2)).unwrap();
+            assert!(matches!(fetched.freshness, Freshness::Stale(_)));
+            assert_eq!(fetched.snapshot, first);
+            assert_eq!(fetched.events.len(), 1);
+        }
+
+        // With nothing to fall back on
+        match revalidate(&first.module, URL, None, ok("{}", None), now(2)) {
+            Err(Error::Json(_)) => {}
+            other => panic!("Expected a JSON error, got {:?}", other),
+        }
+        let not_modified = Ok(Response {
+            status: 304,
+            etag: None,
+            last_modified: None,
+            body: String::new(),
+        });
+        match revalidate(&first.module, URL, None, not_modified, now(2)) {
+            Err(Error::Status { status: 304, .. }) => {}
+            other => panic!("Expected a status error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_revalidate_request() {
+        let request = |previous: Option<Snapshot>, response: Option<&str>| {
+            serde_json::from_value::<RevalidateRequest>(serde_json::json!({
+                "module": "5MBBSStage2",
+                "url": URL,
+                "previous": previous,
+                "response": response.map(|body| serde_json::json!({"status": 200, "etag": null, "body": body})),
+                "error": "TypeError: Failed to fetch",
+            }))
+            .unwrap()
+        };
+        let first = revalidate_request(request(None, Some(EVENTS)), now(1)).unwrap();
+        let json = serde_json::to_value(&first).unwrap();
+        assert_eq!(json["freshness"], "changed");
+        assert_eq!(json["events"][0]["C"], "5MBBS201-OW");
+        assert_eq!(json["snapshot"]["module"], "5MBBSStage2");
+        assert_eq!(json["snapshot"]["body"], serde_json::Value::Null);
+        assert_eq!(json["error"], serde_json::Value::Null);
+
+        let previous = Some(first.snapshot.with_body(EVENTS.to_owned()));
+        let unchanged =
+            revalidate_request(request(previous.clone(), Some(EVENTS)), now(2)).unwrap();
+        assert_eq!(
+            serde_json::to_value(&unchanged).unwrap()["freshness"],
+            "unchanged"
+        );
+
+        let stale = revalidate_request(request(previous, None), now(2)).unwrap();
+        let json = serde_json::to_value(&stale).unwrap();
+        assert_eq!(json["freshness"], "stale");
+        assert_eq!(
+            json["error"],
+            "Could not fetch from KEATS: TypeError: Failed to fetch"
+        );
+
+        match revalidate_request(request(None, None), now(2)) {
+            Err(Error::Unavailable(_)) => {}
+            other => panic!("Expected KEATS to be unavailable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_store() {
+        let directory =
+            std::env::temp_dir().join(format!("adonais_snapshots_{}", std::process::id()));
+        let store = FileStore::new(directory.clone());
+        let module = ModuleId::default();
+        assert_eq!(store.load(&module).unwrap(), None);
+
+        let first = first();
+        store.save(&first).unwrap();
+        assert_eq!(store.load(&module).unwrap(), Some(first));
+        assert_eq!(store.load(&ModuleId::mbbs(3).unwrap()).unwrap(), None);
+
+        fs::write(directory.join("5MBBSStage2.json"), "{").unwrap();
+        assert_eq!(
+            store.load(&module).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_fetch_snapshot() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        use tiny_http::{Header, Server};
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let client =
+            crate::keats::client::Client::new(&format!("http://{}/values", server.server_addr()));
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut available = true;
+            for request in server.incoming_requests() {
+                let if_none_match = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("If-None-Match"))
+                    .map(|header| header.value.to_string());
+                let response = match (available, if_none_match.as_deref()) {
+                    (false, _) => tiny_http::Response::from_string("Down").with_status_code(503),
+                    (true, Some("\"v1\"")) => {
+                        tiny_http::Response::from_string("").with_status_code(304)
+                    }
+                    (true, _) => tiny_http::Response::from_string(EVENTS)
+                        .with_header("ETag: \"v1\"".parse::<Header>().unwrap()),
+                };
+                // Go down after the first revalidation
+                available = if_none_match.is_none();
+                sender.send(if_none_match).unwrap();
+                request.respond(response).unwrap();
+            }
+        });
+
+        let directory =
+            std::env::temp_dir().join(format!("adonais_fetch_snapshot_{}", std::process::id()));
+        let store = FileStore::new(directory.clone());
+        let module = ModuleId::default();
+
+        let fetched = client.fetch_snapshot(&module, &store, now(1)).unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Changed));
+        assert_eq!(receiver.recv().unwrap(), None);
+
+        let fetched = client.fetch_snapshot(&module, &store, now(2)).unwrap();
+        assert!(matches!(fetched.freshness, Freshness::Unchanged));
+        assert_eq!(receiver.recv().unwrap().as_deref(), Some("\"v1\""));
+        assert_eq!(store.load(&module).unwrap().unwrap().fetched, now(2));
+
+        let fetched = client.fetch_snapshot(&module, &store, now(3)).unwrap();
+        match fetched.freshness {
+            Freshness::Stale(Error::Status { status: 503, .. }) => {}
+            other => panic!("Expected a stale snapshot, got {:?}", other),
+        }
+        assert_eq!(fetched.events.len(), 1);
+        // The last good snapshot is kept as it was
+        assert_eq!(store.load(&module).unwrap().unwrap().fetched, now(2));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
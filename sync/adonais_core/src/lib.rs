@@ -11,25 +11,35 @@ extern crate lazy_static;
 #[macro_use]
 extern crate pretty_assertions;
 
+pub mod changes;
+mod error;
+pub mod filter;
 pub mod google;
+mod hash;
+pub mod ical;
 pub mod keats;
+pub mod style;
+pub mod template;
+
+pub use error::Error;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
 
-use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone};
 use chrono_tz::Europe::London;
-use data_encoding::BASE32HEX;
-use siphasher::sip::SipHasher24;
 use wasm_bindgen::prelude::*;
 
-#[derive(Clone, Debug, Hash, PartialEq)]
+use hash::{hash_id, legacy_hash_ids, Encode};
+use keats::groups_parser::{Group, GroupSet};
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct EventInner {
     pub start: DateTime<FixedOffset>,
     pub end: DateTime<FixedOffset>,
     pub code: String,
-    pub groups: Vec<u32>,
+    pub groups: GroupSet,
     pub groups_raw: Option<String>,
     pub title: Option<String>,
     pub type_: Option<String>,
@@ -40,35 +50,221 @@ pub struct EventInner {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Event {
+    /// Stable id, derived from the fields identifying the event.
+    /// Usable as a Google Event id.
     pub id: String,
+    /// Hash of the event's fields from KEATS, which changes whenever any of them do.
+    pub fingerprint: String,
     pub inner: EventInner,
 }
 
-impl TryFrom<keats::Event> for Event {
-    type Error = chrono::ParseError;
+/// Module code, start timestamp and raw groups, identifying an event across id schemes.
+type Provenance<'a> = (&'a str, i64, Option<&'a str>);
+
+impl EventInner {
+    /// The fields that identify an event between KEATS updates.
+    ///
+    /// If anything else changes (such as the room), it is the same event
+    /// with different content.
+    fn identity(&self) -> (&str, &DateTime<FixedOffset>, &Option<String>) {
+        (&self.code, &self.start, &self.groups_raw)
+    }
+}
+
+/// An event as it was in adonais 0.1.0, whose derived `Hash` was its id.
+#[derive(Hash)]
+struct LegacyEventInner<'a> {
+    start: &'a DateTime<FixedOffset>,
+    end: &'a DateTime<FixedOffset>,
+    code: &'a str,
+    groups: Vec<u32>,
+    groups_raw: &'a Option<String>,
+    title: &'a Option<String>,
+    type_: &'a Option<String>,
+    staff: &'a Option<String>,
+    room: &'a Option<String>,
+    campus: &'a Option<String>,
+}
+
+/// The most groups a range could span in adonais 0.1.0, whose only cohort was 200 - 299.
+const LEGACY_MAX_RANGE: u32 = 100;
+
+/// Groups as parsed by adonais 0.1.0, which only knew numbers and ranges, and otherwise assumed 200 - 299.
+///
+/// None if a range is wider than its cohort, as it would never have generated such an event,
+/// and listing every group would exhaust memory.
+fn legacy_groups(groups_raw: Option<&str>) -> Option<Vec<u32>> {
+    let everyone = || Some((200..300).collect());
+    let raw = groups_raw.unwrap_or("").trim();
+    let numeric = raw
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ',' || c == '-' || c.is_whitespace());
+    if raw.is_empty() || !numeric || raw.ends_with('-') {
+        return everyone();
+    }
+    match keats::groups_parser::parse_groups(raw, &Default::default()) {
+        Ok(groups) => {
+            if groups
+                .ranges()
+                .any(|(start, end)| end - start >= LEGACY_MAX_RANGE)
+            {
+                return None;
+            }
+            Some(
+                groups
+                    .ranges()
+                    .flat_map(|(start, end)| start..=end)
+                    .collect(),
+            )
+        }
+        Err(_) => everyone(),
+    }
+}
+
+impl EventInner {
+    /// The ids adonais 0.1.0 gave this event, before provenance was recorded, which hashed every field.
+    ///
+    /// Empty for an event it could not have generated.
+    fn legacy_ids(&self) -> Vec<String> {
+        let groups = match legacy_groups(self.groups_raw.as_deref()) {
+            Some(groups) => groups,
+            None => return vec![],
+        };
+        legacy_hash_ids(&LegacyEventInner {
+            start: &self.start,
+            end: &self.end,
+            code: &self.code,
+            groups,
+            groups_raw: &self.groups_raw,
+            title: &self.title,
+            type_: &self.type_,
+            staff: &self.staff,
+            room: &self.room,
+            campus: &self.campus,
+        })
+    }
+}
+
+/// The fields from KEATS, so the fingerprint changes with them.
+///
+/// Groups are left out, as they are parsed from `groups_raw`.
+impl Encode for EventInner {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (&self.code, &self.start, &self.end, &self.groups_raw).encode(bytes);
+        (
+            &self.title,
+            &self.type_,
+            &self.staff,
+            &self.room,
+            &self.campus,
+        )
+            .encode(bytes);
+    }
+}
+
+impl From<EventInner> for Event {
+    fn from(inner: EventInner) -> Self {
+        Event {
+            id: hash_id(&inner.identity()),
+            fingerprint: hash_id(&inner),
+            inner,
+        }
+    }
+}
+
+fn parse_keats_field<T>(
+    parse: fn(&str, &str) -> chrono::ParseResult<T>,
+    field: &'static str,
+    value: &str,
+    format: &str,
+) -> Result<T, Error> {
+    parse(value, format).map_err(|source| Error::Parse {
+        field,
+        value: value.to_owned(),
+        source,
+    })
+}
+
+/// What was done about a problem with a KEATS event.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The event was left out.
+    Skipped,
+    /// The event was kept, but assigned to all groups.
+    AssignedAllGroups,
+}
+
+/// A problem with a KEATS event, and what was done about it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// The original event, as received from KEATS.
+    pub event: keats::Event,
+    pub reason: String,
+    pub action: Action,
+}
+
+/// A change made to a KEATS event so that it could be kept.
+#[derive(Clone, Debug, PartialEq)]
+struct Repair {
+    reason: String,
+    action: Action,
+}
+
+impl Event {
+    /// Convert a KEATS event, repairing any problems that aren't fatal.
+    ///
+    /// Returns the event, along with the repairs that were made.
+    fn from_keats(
+        event: keats::Event,
+        universe: &keats::groups_parser::GroupUniverse,
+    ) -> Result<(Self, Vec<Repair>), Error> {
+        let mut repairs = vec![];
 
-    fn try_from(event: keats::Event) -> Result<Self, Self::Error> {
         // Timezones! Parse the date and time given a naive London times
         // then convert everything to FixedOffset for consistency.
-        let date = NaiveDate::parse_from_str(&event.date, "%Y-%m-%dT%H:%M:%S")?;
-        let start_time = NaiveTime::parse_from_str(&event.start_time, "%H:%M")?;
-        let end_time = NaiveTime::parse_from_str(&event.end_time, "%H:%M")?;
+        let date = parse_keats_field(
+            NaiveDate::parse_from_str,
+            "Date",
+            &event.date,
+            "%Y-%m-%dT%H:%M:%S",
+        )?;
+        let start_time =
+            parse_keats_field(NaiveTime::parse_from_str, "ST", &event.start_time, "%H:%M")?;
+        let end_time =
+            parse_keats_field(NaiveTime::parse_from_str, "ET", &event.end_time, "%H:%M")?;
+        let start_local = date.and_time(start_time);
         let start = London
-            .from_local_datetime(&date.and_time(start_time))
+            .from_local_datetime(&start_local)
             .earliest()
-            .expect("Start time invalid.")
+            .ok_or(Error::Timezone(start_local))?
             .with_timezone(&FixedOffset::east(0));
+        let end_local = date.and_time(end_time);
         let end = London
-            .from_local_datetime(&date.and_time(end_time))
+            .from_local_datetime(&end_local)
             .latest()
-            .expect("End time invalid.")
+            .ok_or(Error::Timezone(end_local))?
             .with_timezone(&FixedOffset::east(0));
+        if end < start {
+            return Err(Error::EndBeforeStart { start, end });
+        }
 
-        // There's some funky formatting of which groups an event is for
-        let groups =
-            keats::groups_parser::parse_group_range(&event.groups.clone().unwrap_or("".to_owned()));
+        // There's some funky formatting of which groups an event is for.
+        // Missing groups means everyone, but we should know if we couldn't parse them.
+        let groups = match &event.groups {
+            None => universe.groups(),
+            Some(groups_raw) if groups_raw.is_empty() => universe.groups(),
+            Some(groups_raw) => keats::groups_parser::parse_groups(groups_raw, universe)
+                .unwrap_or_else(|error| {
+                    repairs.push(Repair {
+                        reason: format!("Could not parse groups '{}': {}", groups_raw, error),
+                        action: Action::AssignedAllGroups,
+                    });
+                    universe.groups()
+                }),
+        };
 
-        let inner = EventInner {
+        let event = Event::from(EventInner {
             start,
             end,
             code: event.code,
@@ -79,152 +275,581 @@ impl TryFrom<keats::Event> for Event {
             staff: event.staff,
             room: event.room,
             campus: event.campus,
-        };
+        });
+        Ok((event, repairs))
+    }
+}
 
-        // We need a unique id for each event. Hash everything and convert it to
-        // a valid Google Event id format.
-        let mut hasher = SipHasher24::new();
-        inner.hash(&mut hasher);
-        let id = BASE32HEX
-            .encode(&hasher.finish().to_le_bytes())
-            .to_lowercase()
-            .replace("=", "");
+impl TryFrom<keats::Event> for Event {
+    type Error = Error;
 
-        Ok(Event { id, inner })
+    /// Convert a KEATS event, silently repairing any problems that aren't fatal.
+    ///
+    /// Events without groups are assigned to the default `GroupUniverse`.
+    fn try_from(event: keats::Event) -> Result<Self, Self::Error> {
+        Event::from_keats(event, &Default::default()).map(|(event, _)| event)
     }
 }
 
 impl Event {
-    fn has_group(&self, group: u32) -> bool {
-        self.inner.groups.contains(&group)
+    fn has_group(&self, group: &Group) -> bool {
+        self.inner.groups.contains(group)
+    }
+
+    /// Whether the event is for any of the groups the user is assigned to on the day, in London.
+    fn is_assigned(&self, assignments: &[GroupAssignment]) -> bool {
+        let date = self.inner.start.with_timezone(&London).naive_local().date();
+        assignments
+            .iter()
+            .any(|assignment| assignment.applies_on(date) && self.has_group(&assignment.group))
     }
 
     fn is_after(&self, min: &DateTime<FixedOffset>) -> bool {
         &self.inner.end > min
     }
-}
 
-fn join_some_strings(some_strings: Vec<Option<String>>, separator: &str) -> String {
-    let parts = some_strings
-        .into_iter()
-        .filter_map(|o| o)
-        .collect::<Vec<String>>();
-    match parts.len() {
-        0 => "".to_owned(),
-        _ => parts.join(separator),
+    fn is_before(&self, max: &DateTime<FixedOffset>) -> bool {
+        &self.inner.start < max
+    }
+
+    /// The identity of the event, as recorded in generated Google Events.
+    fn provenance(&self) -> Provenance<'_> {
+        (
+            &self.inner.code,
+            self.inner.start.timestamp(),
+            self.inner.groups_raw.as_deref(),
+        )
     }
 }
 
 impl From<Event> for google::Event {
     fn from(event: Event) -> google::Event {
-        let Event { id, inner } = event;
+        event.to_google(&[], &Default::default())
+    }
+}
 
-        let location = join_some_strings(vec![inner.room, inner.campus], ", ");
-        let summary = join_some_strings(
-            vec![
-                Some(inner.title.unwrap_or(inner.code.clone())),
-                inner.groups_raw,
-            ],
-            ", ",
-        );
-        let description = join_some_strings(
-            vec![Some(inner.code.clone()), inner.staff, inner.type_],
-            "\n",
+impl Event {
+    /// Generate a Google event, styled by the user's rules and written out with their templates.
+    pub fn to_google(
+        self,
+        styles: &[style::StyleRule],
+        templates: &template::Templates,
+    ) -> google::Event {
+        let style = style::Style::from_rules(styles, &self.inner);
+        let Event {
+            id,
+            fingerprint,
+            inner,
+        } = self;
+
+        let summary = templates.summary.render(&inner);
+        let description = templates.description.render(&inner);
+        let location = templates.location.render(&inner);
+
+        // Remember what we generated this from, to detect changes and find it again later
+        let mut extended_properties = google::ExtendedProperties::default();
+        let private = &mut extended_properties.private;
+        private.insert(
+            google::VERSION_KEY.to_owned(),
+            google::FORMAT_VERSION.to_owned(),
         );
+        private.insert(google::CODE_KEY.to_owned(), inner.code.clone());
+        if let Some(groups_raw) = &inner.groups_raw {
+            private.insert(google::GROUPS_KEY.to_owned(), groups_raw.clone());
+        }
+        private.insert(google::FINGERPRINT_KEY.to_owned(), fingerprint);
+        // Changing the rules changes the style, which is then regenerated
+        private.insert(google::STYLE_KEY.to_owned(), hash_id(&style));
 
         // Pull other fields together into description
-        google::Event {
+        let mut event = google::Event {
             id,
             summary,
             start: google::Time {
-                datetime: inner.start.to_rfc3339(),
+                time_zone: Some(London.name().to_owned()),
+                ..google::Time::from_datetime(&inner.start)
             },
             end: google::Time {
-                datetime: inner.end.to_rfc3339(),
+                time_zone: Some(London.name().to_owned()),
+                ..google::Time::from_datetime(&inner.end)
             },
             description,
             location,
+            extended_properties,
+            ..Default::default()
+        };
+        style.apply(&mut event);
+
+        // And what we generated, to detect edits by hand
+        let content = event.content_hash();
+        event
+            .extended_properties
+            .private
+            .insert(google::CONTENT_KEY.to_owned(), content);
+        event
+    }
+}
+
+/// Membership of a group, possibly only for some dates, e.g. after switching groups mid-term.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(from = "GroupAssignmentRepr")]
+pub struct GroupAssignment {
+    pub group: Group,
+    /// The first date of membership, if it has not always applied.
+    pub from: Option<NaiveDate>,
+    /// The last date of membership, if it does not always apply.
+    pub until: Option<NaiveDate>,
+}
+
+/// Assignments may be given as just a group, for membership on every date.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GroupAssignmentRepr {
+    Group(Group),
+    Assignment {
+        group: Group,
+        #[serde(default)]
+        from: Option<NaiveDate>,
+        #[serde(default)]
+        until: Option<NaiveDate>,
+    },
+}
+
+impl From<GroupAssignmentRepr> for GroupAssignment {
+    fn from(repr: GroupAssignmentRepr) -> Self {
+        match repr {
+            GroupAssignmentRepr::Group(group) => group.into(),
+            GroupAssignmentRepr::Assignment { group, from, until } => {
+                GroupAssignment { group, from, until }
+            }
         }
     }
 }
 
+impl From<Group> for GroupAssignment {
+    fn from(group: Group) -> Self {
+        GroupAssignment {
+            group,
+            from: None,
+            until: None,
+        }
+    }
+}
+
+impl GroupAssignment {
+    fn applies_on(&self, date: NaiveDate) -> bool {
+        self.from.is_none_or(|from| from <= date) && self.until.is_none_or(|until| date <= until)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct CalendarUpdateRequest {
-    /// Ids of existing events in the Google calendar.
+    /// Existing events in the Google calendar.
     /// If a matching keats event is not found, they will be deleted.
-    pub existing: Vec<String>,
+    pub existing: Vec<google::ExistingEvent>,
     /// New events obtained from the KEATS API.
     /// These will overwrite Google events if they are updated.
     pub new: Vec<keats::Event>,
-    /// Only return events relevant to this group.
-    pub group: u32,
+    /// Only return events relevant to these groups.
+    /// An event is relevant if it is for any group the user is assigned to on the date it starts.
+    pub groups: Vec<GroupAssignment>,
     /// The `timeMin` argument passed to the [Google Events List API](https://developers.google.com/calendar/v3/reference/events/list)
-    /// when generating the list of `existing` ids. Any `new` events before this time will be filtered out.
+    /// when generating the list of `existing` events. Any `new` events before this time will be filtered out.
     pub time_min: DateTime<FixedOffset>,
+    /// The `timeMax` argument passed to the Google Events List API, if any.
+    /// Any `new` events starting at or after this time will be filtered out.
+    #[serde(default)]
+    pub time_max: Option<DateTime<FixedOffset>>,
+    /// Only sync this many weeks after `time_min`, for a rolling window.
+    /// If `time_max` is also given, the earlier of the two applies.
+    #[serde(default)]
+    pub weeks: Option<u32>,
+    /// Every group in the cohort. Events with missing or invalid groups are assigned to all of these.
+    #[serde(default)]
+    pub universe: keats::groups_parser::GroupUniverse,
+    /// Which events to include. By default, those for any of the `groups`.
+    #[serde(default)]
+    pub filter: filter::Filter,
+    /// Colours, reminders and transparency for matching events. Every matching rule applies in order.
+    #[serde(default)]
+    pub styles: Vec<style::StyleRule>,
+    /// How to write the summary, description and location of events.
+    #[serde(default)]
+    pub templates: template::Templates,
+    /// Whether `existing` holds every event in the window.
+    /// If listing stopped early, say after a failed page, this must be false to avoid duplicates.
+    /// Unless it is set, only known events are updated. It must be given, so callers decide.
+    pub existing_complete: bool,
+    /// Whether the calendar only holds events adonais generated, as with the calendar the UI creates.
+    /// Events there which may have been generated by adonais 0.1.0, before anything was recorded about them,
+    /// are then treated as generated, so they are deleted once their KEATS event is gone.
+    #[serde(default)]
+    pub dedicated: bool,
+}
+
+impl CalendarUpdateRequest {
+    /// The end of the sync window, from `time_max` and `weeks`.
+    pub fn window_end(&self) -> Option<DateTime<FixedOffset>> {
+        window_end(&self.time_min, self.time_max, self.weeks)
+    }
+}
+
+/// The end of a sync window starting at `time_min`, which is the earlier of `time_max` and a number of `weeks` later.
+pub fn window_end(
+    time_min: &DateTime<FixedOffset>,
+    time_max: Option<DateTime<FixedOffset>>,
+    weeks: Option<u32>,
+) -> Option<DateTime<FixedOffset>> {
+    let rolling = weeks.map(|weeks| *time_min + Duration::weeks(weeks.into()));
+    match (time_max, rolling) {
+        (Some(time_max), Some(rolling)) => Some(time_max.min(rolling)),
+        (time_max, rolling) => time_max.or(rolling),
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct CalendarUpdateResponse {
     /// New Google Events that should be created.
     pub created: Vec<google::Event>,
+    /// Existing Google Events whose content has changed.
+    /// These should be patched in place, to preserve any user data attached to them.
+    pub updated: Vec<google::Event>,
     /// Existing Google Events that should be deleted.
     pub deleted: Vec<String>,
+    /// KEATS events that were skipped or repaired.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Existing Google Events changed by hand since they were generated.
+    /// These are never patched or deleted, so the changes are kept even if their KEATS event changes.
+    pub edited: Vec<String>,
+    /// Whether `existing` was, or may have been, incomplete, so only updates were calculated.
+    /// Nothing is created or deleted, as events missing from `existing` may still be in the calendar.
+    pub safe_mode: bool,
+}
+
+/// Make sure every event has a unique id.
+///
+/// Events sharing an identity (such as parallel sessions in different rooms) are numbered in order of their end,
+/// then their order in KEATS, and the number is added to their id. Their rooms, staff and so on are left out,
+/// so a parallel session keeps its id when they change, and is patched in place.
+fn disambiguate_ids(events: Vec<Event>) -> Vec<Event> {
+    // Entirely duplicated events are only kept once
+    let mut seen_fingerprints: HashSet<String> = HashSet::new();
+    let mut events: Vec<Event> = events
+        .into_iter()
+        .filter(|event| seen_fingerprints.insert(event.fingerprint.clone()))
+        .collect();
+
+    let mut id_counts: HashMap<String, u32> = HashMap::new();
+    for event in events.iter() {
+        *id_counts.entry(event.id.clone()).or_insert(0) += 1;
+    }
+    let mut order: Vec<usize> = (0..events.len()).collect();
+    order.sort_by_key(|&i| events[i].inner.end);
+    let mut ordinals: HashMap<String, u32> = HashMap::new();
+    for i in order {
+        let event = &mut events[i];
+        if id_counts[&event.id] > 1 {
+            let ordinal = ordinals.entry(event.id.clone()).or_insert(0);
+            let (code, start, groups_raw) = event.inner.identity();
+            event.id = hash_id(&(code, start, groups_raw, *ordinal));
+            *ordinal += 1;
+        }
+    }
+    events
+}
+
+/// Convert KEATS events, and select those the user wants in the window from `time_min` to `time_max`.
+///
+/// Selected events have unique ids. KEATS events that were skipped or repaired are reported as diagnostics.
+pub fn select_events(
+    new: Vec<keats::Event>,
+    groups: &[GroupAssignment],
+    filter: &filter::Filter,
+    universe: &keats::groups_parser::GroupUniverse,
+    time_min: &DateTime<FixedOffset>,
+    time_max: Option<&DateTime<FixedOffset>>,
+) -> (Vec<Event>, Vec<Diagnostic>) {
+    let mut events: Vec<Event> = vec![];
+    let mut diagnostics = vec![];
+    for keats_event in new.into_iter() {
+        match Event::from_keats(keats_event.clone(), universe) {
+            Ok((event, repairs)) => {
+                events.push(event);
+                diagnostics.extend(repairs.into_iter().map(|repair| Diagnostic {
+                    event: keats_event.clone(),
+                    reason: repair.reason,
+                    action: repair.action,
+                }));
+            }
+            Err(error) => diagnostics.push(Diagnostic {
+                event: keats_event,
+                reason: error.to_string(),
+                action: Action::Skipped,
+            }),
+        }
+    }
+
+    // Filtered down to only events for the user now
+    let selected = disambiguate_ids(
+        events
+            .into_iter()
+            .filter(|e| {
+                filter.matches(e, groups)
+                    && e.is_after(time_min)
+                    && time_max.is_none_or(|max| e.is_before(max))
+            })
+            .collect(),
+    );
+    (selected, diagnostics)
 }
 
 /// The main entrypoint of the library.
 ///
 /// Given information from both the KEATS and Google APIs, calculates the diff
 /// that needs to be applied to update the calendar successfully.
-pub fn calculate_calendar_update(request: CalendarUpdateRequest) -> CalendarUpdateResponse {
+/// A bad KEATS event never fails the whole update; it is reported in `diagnostics` instead.
+/// An inconsistent `existing` list does, as acting on it could duplicate or delete events.
+pub fn calculate_calendar_update(
+    request: CalendarUpdateRequest,
+) -> Result<CalendarUpdateResponse, Error> {
+    let time_max = request.window_end();
     let CalendarUpdateRequest {
         existing,
         new,
-        group,
+        groups,
         time_min,
+        universe,
+        filter,
+        styles,
+        templates,
+        existing_complete,
+        dedicated,
+        ..
     } = request;
 
-    let events: Vec<Event> = new
-        .into_iter()
-        .map(|keats_event| Event::try_from(keats_event).unwrap())
-        .collect();
+    // Events without properties in a dedicated calendar are from adonais 0.1.0 if they have the id it gave
+    // some KEATS event, whether or not that event is still selected
+    let legacy_ids: HashSet<String> =
+        if dedicated && existing.iter().any(google::ExistingEvent::may_be_legacy) {
+            new.iter()
+                .filter_map(|e| Event::from_keats(e.clone(), &universe).ok())
+                .flat_map(|(event, _)| event.inner.legacy_ids())
+                .collect()
+        } else {
+            HashSet::new()
+        };
+    let is_generated = |e: &google::ExistingEvent| {
+        e.is_generated() || (e.may_be_legacy() && legacy_ids.contains(&e.id))
+    };
 
-    // Filtered down to only events for the user now
-    let group_events: Vec<Event> = events
-        .into_iter()
-        .filter(|e| e.has_group(group) && e.is_after(&time_min))
-        .collect();
-    let new_ids = group_events.iter().map(|e| e.id.clone()).collect();
+    // The same event listed twice means pages overlapped, so others may be missing
+    let mut existing_by_id: HashMap<&String, &google::ExistingEvent> = HashMap::new();
+    for existing_event in existing.iter() {
+        if existing_by_id
+            .insert(&existing_event.id, existing_event)
+            .is_some()
+        {
+            return Err(Error::DuplicateExisting(existing_event.id.clone()));
+        }
+    }
 
-    let mut new_events_by_id: HashMap<String, Event> = group_events
-        .into_iter()
-        .map(|e| (e.id.clone(), e))
+    let (group_events, diagnostics) = select_events(
+        new,
+        &groups,
+        &filter,
+        &universe,
+        &time_min,
+        time_max.as_ref(),
+    );
+    let new_ids: HashSet<&String> = group_events.iter().map(|e| &e.id).collect();
+
+    // Events we generated under another id, say before ids were derived differently, are found by provenance.
+    // Only unambiguous matches count, as parallel sessions share provenance.
+    let mut new_by_provenance: HashMap<Provenance, usize> = HashMap::new();
+    for event in group_events.iter() {
+        *new_by_provenance.entry(event.provenance()).or_insert(0) += 1;
+    }
+    let mut existing_by_provenance: HashMap<Provenance, Option<&google::ExistingEvent>> =
+        HashMap::new();
+    for existing_event in existing
+        .iter()
+        .filter(|e| e.is_generated() && !new_ids.contains(&e.id))
+    {
+        if let Some(provenance) = existing_event.provenance() {
+            existing_by_provenance
+                .entry(provenance)
+                .and_modify(|unique| *unique = None)
+                .or_insert(Some(existing_event));
+        }
+    }
+    // Events generated before provenance was recorded have none, but are found by the id they were given then
+    let untagged_by_id: HashMap<&str, &google::ExistingEvent> = existing
+        .iter()
+        .filter(|e| e.fingerprint().is_none() && !new_ids.contains(&e.id))
+        .map(|e| (e.id.as_str(), e))
+        .collect();
+    let matches: Vec<Option<&google::ExistingEvent>> = group_events
+        .iter()
+        .map(|event| {
+            existing_by_id
+                .get(&event.id)
+                .copied()
+                .or_else(|| {
+                    let provenance = event.provenance();
+                    match new_by_provenance[&provenance] {
+                        1 => existing_by_provenance.get(&provenance).copied().flatten(),
+                        _ => None,
+                    }
+                })
+                .or_else(|| {
+                    event
+                        .inner
+                        .legacy_ids()
+                        .iter()
+                        .find_map(|id| untagged_by_id.get(id.as_str()).copied())
+                })
+        })
         .collect();
 
-    let existing_ids: HashSet<String> = existing.into_iter().collect();
+    // A listing with events we generated outside the window was not listed for it, so may be missing events
+    let outside_window = existing
+        .iter()
+        .any(|e| is_generated(e) && !e.is_within(&time_min, time_max.as_ref()));
+    let safe_mode = !existing_complete || outside_window;
+    let mut matched_ids: HashSet<&String> = HashSet::new();
+    let mut created = vec![];
+    let mut updated = vec![];
+    for (event, existing_event) in group_events.into_iter().zip(matches) {
+        match existing_event {
+            None if safe_mode => {}
+            None => created.push(event.to_google(&styles, &templates)),
+            Some(existing_event) => {
+                matched_ids.insert(&existing_event.id);
+                // Patching would undo the changes made by hand, so they are only reported
+                if existing_event.is_edited() {
+                    continue;
+                }
+                let fingerprint_changed = existing_event.fingerprint() != Some(&event.fingerprint);
+                let generated = event.to_google(&styles, &templates);
+                let private = |key| {
+                    generated
+                        .extended_properties
+                        .private
+                        .get(key)
+                        .map(|value: &String| value.as_str())
+                };
+                // Regenerate anything from another version, style or template too, as the format has changed
+                let format_changed = existing_event.version() != Some(google::FORMAT_VERSION)
+                    || existing_event.style() != private(google::STYLE_KEY)
+                    || existing_event
+                        .content()
+                        .is_some_and(|content| Some(content) != private(google::CONTENT_KEY));
+                if fingerprint_changed || format_changed {
+                    updated.push(google::Event {
+                        id: existing_event.id.clone(),
+                        ..generated
+                    })
+                }
+            }
+        }
+    }
 
-    let deleted_ids: Vec<&String> = existing_ids.difference(&new_ids).collect();
-    let created_ids: Vec<&String> = new_ids.difference(&existing_ids).collect();
+    // Only delete events we generated in the window, which nobody has edited since
+    let deleted = existing
+        .iter()
+        .filter(|e| {
+            !safe_mode
+                && !matched_ids.contains(&e.id)
+                && is_generated(e)
+                && !e.is_edited()
+                && e.is_within(&time_min, time_max.as_ref())
+        })
+        .map(|e| e.id.clone())
+        .collect();
+    let edited = existing
+        .iter()
+        .filter(|e| e.is_edited())
+        .map(|e| e.id.clone())
+        .collect();
 
-    CalendarUpdateResponse {
-        created: created_ids
-            .into_iter()
-            .map(|id| {
-                google::Event::from(
-                    new_events_by_id
-                        .remove(id)
-                        .expect("Created event not in map."),
-                )
-            })
-            .collect(),
-        deleted: deleted_ids.into_iter().map(|id| id.to_owned()).collect(),
+    Ok(CalendarUpdateResponse {
+        created,
+        updated,
+        deleted,
+        edited,
+        diagnostics,
+        safe_mode,
+    })
+}
+
+impl From<Error> for JsValue {
+    fn from(error: Error) -> JsValue {
+        JsValue::from_str(&error.to_string())
     }
 }
 
+/// Wasm binding for `calculate_calendar_update`.
+///
+/// Any error is thrown as a string describing the problem.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn calculate_calendar_update_wasm(js_value: &JsValue) -> Result<JsValue, JsValue> {
+    let request = js_value.into_serde().map_err(Error::from)?;
+    let response = calculate_calendar_update(request)?;
+    Ok(JsValue::from_serde(&response).map_err(Error::from)?)
+}
+
+/// Wasm binding for `ical::calculate_ical`, stamped with the current time.
+///
+/// The returned `sequences` should be stored and passed back with the next request.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn calculate_ical_wasm(js_value: &JsValue) -> Result<JsValue, JsValue> {
+    let request = js_value.into_serde().map_err(Error::from)?;
+    let response = ical::calculate_ical(request, &chrono::Utc::now());
+    Ok(JsValue::from_serde(&response).map_err(Error::from)?)
+}
+
+/// Wasm binding for `keats::snapshot::revalidate_request`, for responses fetched by the browser.
+///
+/// Unless it is stale, the returned `snapshot` should be stored with its body and passed back as `previous` next time.
 #[wasm_bindgen]
-pub fn calculate_calendar_update_wasm(js_value: &JsValue) -> JsValue {
-    let request = js_value.into_serde().unwrap();
-    let response = calculate_calendar_update(request);
-    JsValue::from_serde(&response).unwrap()
+#[allow(deprecated)]
+pub fn revalidate_keats_wasm(js_value: &JsValue) -> Result<JsValue, JsValue> {
+    let request = js_value.into_serde().map_err(Error::from)?;
+    let response = keats::snapshot::revalidate_request(request, chrono::Utc::now())
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(JsValue::from_serde(&response).map_err(Error::from)?)
+}
+
+/// The URL of a KEATS module's timetable, like `5MBBSStage2`, under `base_url`, which may be a proxy for KEATS.
+///
+/// An invalid module is thrown as a string describing the problem.
+#[wasm_bindgen]
+pub fn keats_url_wasm(base_url: &str, module: &str) -> Result<String, JsValue> {
+    let module: keats::client::ModuleId = module
+        .parse()
+        .map_err(|error: keats::client::Error| JsValue::from_str(&error.to_string()))?;
+    Ok(keats::client::Client::new(base_url).url(&module))
+}
+
+/// Wasm binding for `keats::client::decode`, for a timetable fetched by the browser.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn decode_keats_wasm(body: &str) -> Result<JsValue, JsValue> {
+    let events =
+        keats::client::decode(body).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(JsValue::from_serde(&events).map_err(Error::from)?)
+}
+
+/// Format an array of groups like `[3, 7, 8, 9, "A1"]` as a canonical string like `3, 7-9, A1`.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn format_groups_wasm(js_value: &JsValue) -> Result<String, JsValue> {
+    let groups: Vec<Group> = js_value.into_serde().map_err(Error::from)?;
+    Ok(keats::groups_parser::format_groups(
+        &groups.into_iter().collect(),
+    ))
 }
 
 #[cfg(test)]
@@ -232,6 +857,76 @@ mod tests {
 
     use super::*;
 
+    /// Properties of an event generated now, for groups `253-256`.
+    fn extended_properties(code: &str, fingerprint: &str) -> google::ExtendedProperties {
+        let mut extended_properties = google::ExtendedProperties::default();
+        let unstyled = hash_id(&style::Style::default());
+        for (key, value) in &[
+            (google::VERSION_KEY, google::FORMAT_VERSION),
+            (google::CODE_KEY, code),
+            (google::GROUPS_KEY, "253-256"),
+            (google::FINGERPRINT_KEY, fingerprint),
+            (google::STYLE_KEY, &unstyled),
+        ] {
+            extended_properties
+                .private
+                .insert((*key).to_owned(), (*value).to_owned());
+        }
+        extended_properties
+    }
+
+    /// An event generated now if it has a fingerprint, or created by hand if not.
+    fn existing_event(id: &str, fingerprint: Option<&str>) -> google::ExistingEvent {
+        let mut existing_event = google::ExistingEvent {
+            id: id.to_owned(),
+            ..Default::default()
+        };
+        if let Some(fingerprint) = fingerprint {
+            let private = &mut existing_event.extended_properties.private;
+            private.insert(
+                google::VERSION_KEY.to_owned(),
+                google::FORMAT_VERSION.to_owned(),
+            );
+            private.insert(google::FINGERPRINT_KEY.to_owned(), fingerprint.to_owned());
+            private.insert(
+                google::STYLE_KEY.to_owned(),
+                hash_id(&style::Style::default()),
+            );
+        }
+        existing_event
+    }
+
+    /// A generated event, as listed back from the calendar.
+    fn listed(event: &google::Event) -> google::ExistingEvent {
+        let time = |time: &google::Time| google::ExistingTime {
+            datetime: time
+                .datetime
+                .as_ref()
+                .map(|datetime| DateTime::parse_from_rfc3339(datetime).unwrap()),
+            date: None,
+        };
+        google::ExistingEvent {
+            id: event.id.clone(),
+            start: time(&event.start),
+            end: time(&event.end),
+            summary: event.summary.clone(),
+            description: event.description.clone(),
+            location: event.location.clone(),
+            extended_properties: event.extended_properties.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Add the hash of generated content, which depends on all the other fields.
+    fn generated(mut event: google::Event) -> google::Event {
+        let content = event.content_hash();
+        event
+            .extended_properties
+            .private
+            .insert(google::CONTENT_KEY.to_owned(), content);
+        event
+    }
+
     lazy_static! {
         static ref BASE_KEATS_EVENT: keats::Event = {
             keats::Event {
@@ -249,12 +944,17 @@ mod tests {
         };
         static ref BASE_EVENT: Event = {
             Event {
-                id: "m9p6fjn06olgm".to_owned(),
+                id: "viikm4g4jp6u8".to_owned(),
+                fingerprint: "vu5lnp69b38me".to_owned(),
                 inner: EventInner {
                     start: DateTime::parse_from_rfc3339("2017-11-12T14:03:00+00:00").unwrap(),
                     end: DateTime::parse_from_rfc3339("2017-11-12T15:00:00+00:00").unwrap(),
                     code: "CODE001".to_owned(),
-                    groups: vec![253, 254, 255, 256],
+                    groups: [253, 254, 255, 256]
+                        .iter()
+                        .cloned()
+                        .map(Group::from)
+                        .collect(),
                     groups_raw: Some("253-256".to_owned()),
                     title: Some("Introduction to Clinical Pharmacology".to_owned()),
                     type_: Some("Lecture".to_owned()),
@@ -265,21 +965,50 @@ mod tests {
             }
         };
         static ref BASE_GOOGLE_EVENT: google::Event = {
-            google::Event {
-                id: "m9p6fjn06olgm".to_owned(),
+            generated(google::Event {
+                id: "viikm4g4jp6u8".to_owned(),
                 start: google::Time {
-                    datetime: "2017-11-12T14:03:00+00:00".to_owned(),
+                    datetime: Some("2017-11-12T14:03:00+00:00".to_owned()),
+                    time_zone: Some("Europe/London".to_owned()),
+                    ..Default::default()
                 },
                 end: google::Time {
-                    datetime: "2017-11-12T15:00:00+00:00".to_owned(),
+                    datetime: Some("2017-11-12T15:00:00+00:00".to_owned()),
+                    time_zone: Some("Europe/London".to_owned()),
+                    ..Default::default()
                 },
                 summary: "Introduction to Clinical Pharmacology, 253-256".to_owned(),
                 description: "CODE001\nJohn Keats\nLecture".to_owned(),
                 location: "Room 3b, Unseen University".to_owned(),
-            }
+                reminders: Some(google::Reminders {
+                    use_default: true,
+                    overrides: vec![],
+                }),
+                transparency: Some(google::Transparency::Opaque),
+                extended_properties: extended_properties("CODE001", "vu5lnp69b38me"),
+                ..Default::default()
+            })
         };
     }
 
+    /// A request for group 253 from 2017, with a complete listing of no existing events.
+    fn base_request() -> CalendarUpdateRequest {
+        CalendarUpdateRequest {
+            existing: vec![],
+            new: vec![],
+            groups: vec![Group::from(253).into()],
+            time_min: DateTime::parse_from_rfc3339("2017-01-01T00:00:00+00:00").unwrap(),
+            time_max: None,
+            weeks: None,
+            universe: Default::default(),
+            filter: Default::default(),
+            styles: vec![],
+            templates: Default::default(),
+            existing_complete: true,
+            dedicated: false,
+        }
+    }
+
     #[test]
     fn test_event_from_keats_event() {
         // All fields present
@@ -297,18 +1026,34 @@ mod tests {
             .unwrap()
             .inner,
             EventInner {
-                groups: (200..300).collect(),
+                groups: (200..300).map(Group::from).collect(),
                 groups_raw: None,
                 ..BASE_EVENT.inner.clone()
             }
         );
 
         // Time parse error is raised up
-        assert!(Event::try_from(keats::Event {
-            date: "spam".to_owned(),
-            ..BASE_KEATS_EVENT.clone()
-        })
-        .is_err())
+        assert_eq!(
+            Event::try_from(keats::Event {
+                date: "spam".to_owned(),
+                ..BASE_KEATS_EVENT.clone()
+            })
+            .unwrap_err()
+            .to_string(),
+            "Could not parse field 'Date' with value 'spam'"
+        );
+
+        // Nonexistent local times are raised up
+        assert_eq!(
+            Event::try_from(keats::Event {
+                date: "2019-03-31T00:00:00".to_owned(),
+                start_time: "01:30".to_owned(),
+                ..BASE_KEATS_EVENT.clone()
+            })
+            .unwrap_err()
+            .to_string(),
+            "Local time '2019-03-31 01:30:00' does not exist in Europe/London"
+        );
     }
 
     #[test]
@@ -323,54 +1068,115 @@ mod tests {
         assert_eq!(
             google::Event::from(Event {
                 id: "id1".to_owned(),
+                fingerprint: "vu5lnp69b38me".to_owned(),
                 inner: EventInner {
                     start: DateTime::parse_from_rfc3339("2019-08-12T14:03:00+01:00").unwrap(),
                     end: DateTime::parse_from_rfc3339("2019-08-12T15:00:00+01:00").unwrap(),
                     ..BASE_EVENT.inner.clone()
                 }
             }),
-            google::Event {
+            generated(google::Event {
                 id: "id1".to_owned(),
                 start: google::Time {
-                    datetime: "2019-08-12T14:03:00+01:00".to_owned(),
+                    datetime: Some("2019-08-12T14:03:00+01:00".to_owned()),
+                    time_zone: Some("Europe/London".to_owned()),
+                    ..Default::default()
                 },
                 end: google::Time {
-                    datetime: "2019-08-12T15:00:00+01:00".to_owned(),
+                    datetime: Some("2019-08-12T15:00:00+01:00".to_owned()),
+                    time_zone: Some("Europe/London".to_owned()),
+                    ..Default::default()
                 },
                 ..BASE_GOOGLE_EVENT.clone()
-            }
+            })
+        );
+
+        // Groups are normalised in the summary, but left out when for everyone
+        assert_eq!(
+            google::Event::from(Event {
+                id: "id1".to_owned(),
+                fingerprint: "vu5lnp69b38me".to_owned(),
+                inner: EventInner {
+                    groups_raw: Some("256, 253 - 255 253".to_owned()),
+                    ..BASE_EVENT.inner.clone()
+                }
+            })
+            .summary,
+            "Introduction to Clinical Pharmacology, 253-256"
+        );
+        assert_eq!(
+            google::Event::from(Event {
+                id: "id1".to_owned(),
+                fingerprint: "vu5lnp69b38me".to_owned(),
+                inner: EventInner {
+                    groups_raw: None,
+                    ..BASE_EVENT.inner.clone()
+                }
+            })
+            .summary,
+            "Introduction to Clinical Pharmacology"
         );
 
         // Description & location concat nicely
         assert_eq!(
             google::Event::from(Event {
                 id: "id2".to_owned(),
+                fingerprint: "vu5lnp69b38me".to_owned(),
                 inner: EventInner {
                     staff: None,
                     room: None,
                     ..BASE_EVENT.inner.clone()
                 }
             }),
-            google::Event {
+            generated(google::Event {
                 id: "id2".to_owned(),
                 description: "CODE001\nLecture".to_owned(),
                 location: "Unseen University".to_owned(),
                 ..BASE_GOOGLE_EVENT.clone()
-            }
+            })
         );
     }
 
+    #[test]
+    fn test_event_id_is_stable() {
+        // Changing the room keeps the id, but changes the fingerprint
+        let moved = Event::try_from(keats::Event {
+            room: Some("Room 4".to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        })
+        .unwrap();
+        assert_eq!(moved.id, BASE_EVENT.id);
+        assert_ne!(moved.fingerprint, BASE_EVENT.fingerprint);
+
+        // Changing the start time is a different event
+        let rescheduled = Event::try_from(keats::Event {
+            start_time: "14:30".to_owned(),
+            ..BASE_KEATS_EVENT.clone()
+        })
+        .unwrap();
+        assert_ne!(rescheduled.id, BASE_EVENT.id);
+    }
+
     #[test]
     fn test_calclate_calendar_update() {
         // - the base event is unchanged
         // - "existing1" has been deleted
+        // - "foreign1" was not generated by us, so is left alone
         // - "New Event" is created with a new id
+        // - "Moved Event" has changed room, and is updated in place
         assert_eq!(
             calculate_calendar_update(CalendarUpdateRequest {
                 new: vec![
                     BASE_KEATS_EVENT.clone(),
                     keats::Event {
                         title: Some("New Event".to_owned()),
+                        code: "CODE002".to_owned(),
+                        ..BASE_KEATS_EVENT.clone()
+                    },
+                    keats::Event {
+                        title: Some("Moved Event".to_owned()),
+                        code: "CODE003".to_owned(),
+                        room: Some("Room 4".to_owned()),
                         ..BASE_KEATS_EVENT.clone()
                     },
                     keats::Event {
@@ -379,18 +1185,754 @@ mod tests {
                         ..BASE_KEATS_EVENT.clone()
                     },
                 ],
-                existing: vec![BASE_GOOGLE_EVENT.id.clone(), "existing1".to_string(),],
-                group: 253,
-                time_min: DateTime::parse_from_rfc3339("2017-01-01T00:00:00+00:00").unwrap(),
-            }),
+                existing: vec![
+                    existing_event(&BASE_GOOGLE_EVENT.id, Some(&BASE_EVENT.fingerprint)),
+                    existing_event("existing1", Some("oldfingerprint1")),
+                    existing_event("f38e8dlh92i90", Some("oldfingerprint")),
+                    existing_event("foreign1", None),
+                ],
+                ..base_request()
+            })
+            .unwrap(),
             CalendarUpdateResponse {
-                created: vec![google::Event {
-                    id: "e0ko7t238tm42".to_owned(),
+                created: vec![generated(google::Event {
+                    id: "c1mq3qlhl5j38".to_owned(),
                     summary: "New Event, 253-256".to_owned(),
+                    description: "CODE002\nJohn Keats\nLecture".to_owned(),
+                    extended_properties: extended_properties("CODE002", "p5eeetsarnjls"),
+                    ..BASE_GOOGLE_EVENT.clone()
+                })],
+                updated: vec![generated(google::Event {
+                    id: "f38e8dlh92i90".to_owned(),
+                    summary: "Moved Event, 253-256".to_owned(),
+                    description: "CODE003\nJohn Keats\nLecture".to_owned(),
+                    location: "Room 4, Unseen University".to_owned(),
+                    extended_properties: extended_properties("CODE003", "e65dkpin5lcpa"),
                     ..BASE_GOOGLE_EVENT.clone()
-                }],
+                })],
                 deleted: vec!["existing1".to_string()],
+                edited: vec![],
+                diagnostics: vec![],
+                safe_mode: false,
+            }
+        )
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_styles() {
+        let styles: Vec<style::StyleRule> =
+            serde_json::from_str(r#"[{"type": "lecture", "color_id": "9", "reminders": [30]}]"#)
+                .unwrap();
+        let request = CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![existing_event(
+                &BASE_GOOGLE_EVENT.id,
+                Some(&BASE_EVENT.fingerprint),
+            )],
+            styles: styles.clone(),
+            ..base_request()
+        };
+
+        // An unchanged event is patched when the rules style it differently
+        let updated = calculate_calendar_update(request.clone()).unwrap().updated;
+        assert_eq!(updated.len(), 1);
+        let styled = &updated[0];
+        assert_eq!(styled.summary, BASE_GOOGLE_EVENT.summary);
+        let patch = serde_json::to_value(styled).unwrap();
+        assert_eq!(patch["colorId"], "9");
+        assert_eq!(
+            patch["reminders"],
+            serde_json::json!({"useDefault": false, "overrides": [{"method": "popup", "minutes": 30}]})
+        );
+        assert_eq!(patch["transparency"], "opaque");
+
+        // But not again once it has that style
+        let mut existing = existing_event(&BASE_GOOGLE_EVENT.id, Some(&BASE_EVENT.fingerprint));
+        existing.extended_properties.private.insert(
+            google::STYLE_KEY.to_owned(),
+            styled.extended_properties.private[google::STYLE_KEY].clone(),
+        );
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            existing: vec![existing.clone()],
+            ..request.clone()
+        })
+        .unwrap();
+        assert_eq!(response.updated, vec![]);
+
+        // Removing the rules resets the style, explicitly as patches keep fields left out
+        let request = CalendarUpdateRequest {
+            existing: vec![existing],
+            styles: vec![],
+            ..request
+        };
+        let updated = calculate_calendar_update(request.clone()).unwrap().updated;
+        assert_eq!(updated, vec![BASE_GOOGLE_EVENT.clone()]);
+        let patch = serde_json::to_value(&updated[0]).unwrap();
+        assert_eq!(patch["colorId"], serde_json::Value::Null);
+        assert_eq!(
+            patch["reminders"],
+            serde_json::json!({"useDefault": true, "overrides": []})
+        );
+        assert_eq!(patch["transparency"], "opaque");
+        let unstyled = hash_id(&style::Style::default());
+        assert_eq!(patch["extendedProperties"]["private"]["style"], unstyled);
+
+        // And once reset, it is left alone
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            existing: vec![existing_event(
+                &BASE_GOOGLE_EVENT.id,
+                Some(&BASE_EVENT.fingerprint),
+            )],
+            ..request
+        })
+        .unwrap();
+        assert_eq!(response.updated, vec![]);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_templates() {
+        // Generated with the default templates
+        let existing = listed(&BASE_GOOGLE_EVENT);
+        let request = CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![existing],
+            ..base_request()
+        };
+        assert_eq!(
+            calculate_calendar_update(request.clone()).unwrap().updated,
+            vec![]
+        );
+
+        // Changing the templates rewrites the event
+        let templates: template::Templates =
+            serde_json::from_str(r#"{"summary": "[{type}] {title|code}", "location": "{campus}"}"#)
+                .unwrap();
+        let updated = calculate_calendar_update(CalendarUpdateRequest {
+            templates,
+            ..request
+        })
+        .unwrap()
+        .updated;
+        assert_eq!(
+            updated,
+            vec![generated(google::Event {
+                summary: "[Lecture] Introduction to Clinical Pharmacology".to_owned(),
+                location: "Unseen University".to_owned(),
+                extended_properties: extended_properties("CODE001", "vu5lnp69b38me"),
+                ..BASE_GOOGLE_EVENT.clone()
+            })]
+        );
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_existing_scope() {
+        let existing_at = |id: &str, start: &str, end: &str| google::ExistingEvent {
+            start: google::ExistingTime {
+                datetime: Some(DateTime::parse_from_rfc3339(start).unwrap()),
+                date: None,
+            },
+            end: google::ExistingTime {
+                datetime: Some(DateTime::parse_from_rfc3339(end).unwrap()),
+                date: None,
+            },
+            ..existing_event(id, Some("oldfingerprint"))
+        };
+        let mut edited = existing_at("edited", "2017-11-13T09:00:00Z", "2017-11-13T10:00:00Z");
+        edited
+            .extended_properties
+            .private
+            .insert(google::CONTENT_KEY.to_owned(), "oldcontent".to_owned());
+
+        // Generated by adonais 0.1.0 for an event another group now has, and one it never generated
+        let other_group = keats::Event {
+            groups: Some("200".to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let (other_event, _) = Event::from_keats(other_group.clone(), &Default::default()).unwrap();
+        let legacy_at = |id: &str| google::ExistingEvent {
+            extended_properties: Default::default(),
+            ..existing_at(id, "2017-11-14T09:00:00Z", "2017-11-14T10:00:00Z")
+        };
+        let legacy_id = other_event.inner.legacy_ids()[0].clone();
+
+        let request = CalendarUpdateRequest {
+            existing: vec![
+                existing_at("during", "2017-11-12T09:00:00Z", "2017-11-12T10:00:00Z"),
+                edited,
+                legacy_at(&legacy_id),
+                legacy_at("0k8l3bt5nm42e"),
+                google::ExistingEvent {
+                    start: google::ExistingTime {
+                        datetime: Some(
+                            DateTime::parse_from_rfc3339("2017-10-01T09:00:00Z").unwrap(),
+                        ),
+                        date: None,
+                    },
+                    ..existing_event("by hand", None)
+                },
+            ],
+            new: vec![other_group],
+            time_min: DateTime::parse_from_rfc3339("2017-11-06T00:00:00+00:00").unwrap(),
+            time_max: Some(DateTime::parse_from_rfc3339("2017-11-20T00:00:00+00:00").unwrap()),
+            ..base_request()
+        };
+        let response = calculate_calendar_update(request.clone()).unwrap();
+
+        // Only untouched events we generated are deleted
+        assert_eq!(response.deleted, vec!["during"]);
+        assert_eq!(response.edited, vec!["edited"]);
+
+        // In a calendar only adonais uses, events without properties are from adonais 0.1.0,
+        // but only if they have the id it gave a KEATS event
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            dedicated: true,
+            ..request
+        })
+        .unwrap();
+        assert_eq!(response.deleted, vec!["during".to_owned(), legacy_id]);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_keeps_edits() {
+        // The room has changed in KEATS, but notes were added to the event by hand
+        let mut existing = google::ExistingEvent {
+            summary: "Introduction to Clinical Pharmacology (bring notes)".to_owned(),
+            ..listed(&BASE_GOOGLE_EVENT)
+        };
+        existing.extended_properties.private.insert(
+            google::FINGERPRINT_KEY.to_owned(),
+            "oldfingerprint".to_owned(),
+        );
+
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![existing],
+            ..base_request()
+        })
+        .unwrap();
+
+        // It is only reported, as patching it would lose the notes
+        assert_eq!(response.updated, vec![]);
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+        assert_eq!(response.edited, vec![BASE_EVENT.id.clone()]);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_migration() {
+        // Generated by an older version under an old id scheme, but with provenance
+        let mut legacy = existing_event("legacyid", Some(&BASE_EVENT.fingerprint));
+        legacy.start.datetime = Some(BASE_EVENT.inner.start);
+        let private = &mut legacy.extended_properties.private;
+        private.insert(google::VERSION_KEY.to_owned(), "0.0.1".to_owned());
+        private.insert(google::CODE_KEY.to_owned(), "CODE001".to_owned());
+        private.insert(google::GROUPS_KEY.to_owned(), "253-256".to_owned());
+
+        let request = CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![legacy.clone()],
+            ..base_request()
+        };
+
+        // It is regenerated in place, rather than orphaned or deleted and recreated
+        let response = calculate_calendar_update(request.clone()).unwrap();
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+        assert_eq!(
+            response.updated,
+            vec![google::Event {
+                id: "legacyid".to_owned(),
+                ..BASE_GOOGLE_EVENT.clone()
+            }]
+        );
+
+        // Once up to date, it is left alone
+        legacy.extended_properties.private.insert(
+            google::VERSION_KEY.to_owned(),
+            google::FORMAT_VERSION.to_owned(),
+        );
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            existing: vec![legacy.clone()],
+            ..request.clone()
+        })
+        .unwrap();
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.updated, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+
+        // Parallel sessions share provenance, so can't be matched
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![
+                BASE_KEATS_EVENT.clone(),
+                keats::Event {
+                    room: Some("Room 4".to_owned()),
+                    ..BASE_KEATS_EVENT.clone()
+                },
+            ],
+            existing: vec![legacy],
+            ..request
+        })
+        .unwrap();
+        assert_eq!(response.created.len(), 2);
+        assert_eq!(response.deleted, vec!["legacyid"]);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_incomplete_existing() {
+        let request = CalendarUpdateRequest {
+            new: vec![
+                keats::Event {
+                    room: Some("Room 4".to_owned()),
+                    ..BASE_KEATS_EVENT.clone()
+                },
+                keats::Event {
+                    code: "CODE002".to_owned(),
+                    ..BASE_KEATS_EVENT.clone()
+                },
+            ],
+            existing: vec![
+                existing_event(&BASE_GOOGLE_EVENT.id, Some(&BASE_EVENT.fingerprint)),
+                existing_event("existing1", None),
+            ],
+            existing_complete: false,
+            ..base_request()
+        };
+
+        // Only known events are updated, as others may be on a page that wasn't listed
+        let response = calculate_calendar_update(request.clone()).unwrap();
+        assert!(response.safe_mode);
+        assert_eq!(
+            response
+                .updated
+                .iter()
+                .map(|event| event.id.as_str())
+                .collect::<Vec<_>>(),
+            vec![BASE_EVENT.id.as_str()]
+        );
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+
+        // Overlapping pages are refused outright
+        let mut existing = request.existing.clone();
+        existing.push(existing_event("existing1", None));
+        let error = calculate_calendar_update(CalendarUpdateRequest {
+            existing,
+            existing_complete: true,
+            dedicated: false,
+            ..request
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Existing event 'existing1' was listed more than once, so the list is inconsistent"
+        );
+    }
+
+    #[test]
+    fn test_legacy_ids() {
+        // As given by adonais 0.1.0 in the browser, and in its own tests
+        assert_eq!(
+            BASE_EVENT.inner.legacy_ids(),
+            vec!["61ni7ooh2qhgu", "m9p6fjn06olgm"]
+        );
+        assert_eq!(legacy_groups(Some(" 253-256, 260")).unwrap().len(), 5);
+        assert_eq!(legacy_groups(None), Some((200..300).collect()));
+        for unknown in &["253a", "250-", "201-205 except 203"] {
+            assert_eq!(legacy_groups(Some(unknown)), Some((200..300).collect()));
+        }
+
+        // A huge range is never listed, as adonais 0.1.0 could not have generated its event
+        assert_eq!(legacy_groups(Some("0-4000000000")), None);
+        assert_eq!(legacy_groups(Some("200-299")).unwrap().len(), 100);
+        let huge = keats::Event {
+            groups: Some("0-4000000000".to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let (event, _) = Event::from_keats(huge.clone(), &Default::default()).unwrap();
+        assert_eq!(event.inner.legacy_ids(), Vec::<String>::new());
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![huge],
+            existing: vec![existing_event("61ni7ooh2qhgu", None)],
+            groups: vec![Group::from(3_000_000_000).into()],
+            dedicated: true,
+            ..base_request()
+        })
+        .unwrap();
+        assert_eq!(response.created.len(), 1);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_legacy_migration() {
+        // Generated by adonais 0.1.0 in the browser, without any extended properties
+        let legacy = google::ExistingEvent {
+            id: "61ni7ooh2qhgu".to_owned(),
+            start: google::ExistingTime {
+                datetime: Some(BASE_EVENT.inner.start),
+                date: None,
+            },
+            summary: "Introduction to Clinical Pharmacology, 253-256".to_owned(),
+            ..Default::default()
+        };
+        let request = CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![legacy.clone()],
+            ..base_request()
+        };
+
+        // It is tagged in place, rather than duplicated
+        let response = calculate_calendar_update(request.clone()).unwrap();
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+        assert_eq!(
+            response.updated,
+            vec![google::Event {
+                id: legacy.id.clone(),
+                ..BASE_GOOGLE_EVENT.clone()
+            }]
+        );
+
+        // Unless its KEATS event has changed since, when it no longer matches
+        let moved = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![keats::Event {
+                room: Some("Room 4".to_owned()),
+                ..BASE_KEATS_EVENT.clone()
+            }],
+            ..request
+        })
+        .unwrap();
+        assert_eq!(moved.created.len(), 1);
+        assert_eq!(moved.updated, vec![]);
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_inconsistent_existing() {
+        let existing_at = |id: &str, start: &str| google::ExistingEvent {
+            start: google::ExistingTime {
+                datetime: Some(DateTime::parse_from_rfc3339(start).unwrap()),
+                date: None,
+            },
+            ..existing_event(id, Some("oldfingerprint"))
+        };
+        let request = CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone()],
+            existing: vec![
+                existing_at("during", "2017-11-12T09:00:00Z"),
+                existing_at("also during", "2017-11-13T09:00:00Z"),
+            ],
+            time_min: DateTime::parse_from_rfc3339("2017-11-06T00:00:00+00:00").unwrap(),
+            time_max: Some(DateTime::parse_from_rfc3339("2017-11-20T00:00:00+00:00").unwrap()),
+            ..base_request()
+        };
+        let response = calculate_calendar_update(request.clone()).unwrap();
+        assert!(!response.safe_mode);
+        assert_eq!(response.created.len(), 1);
+        assert_eq!(response.deleted, vec!["during", "also during"]);
+
+        // An event we generated outside the window means it was listed for another window
+        let mut existing = request.existing.clone();
+        existing.push(existing_at("after", "2017-12-01T09:00:00Z"));
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            existing,
+            ..request.clone()
+        })
+        .unwrap();
+        assert!(response.safe_mode);
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+
+        // Whether the listing was complete must be said
+        let error = serde_json::from_str::<CalendarUpdateRequest>(
+            r#"{"existing": [], "new": [], "groups": [253], "time_min": "2017-11-06T00:00:00Z"}"#,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("missing field `existing_complete`"));
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_diagnostics() {
+        let bad_time = keats::Event {
+            end_time: "25:00".to_owned(),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let backwards = keats::Event {
+            end_time: "13:00".to_owned(),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let bad_groups = keats::Event {
+            groups: Some("253, spam".to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![
+                BASE_KEATS_EVENT.clone(),
+                bad_time.clone(),
+                backwards.clone(),
+                bad_groups.clone(),
+            ],
+            existing: vec![existing_event(
+                &BASE_GOOGLE_EVENT.id,
+                Some(&BASE_EVENT.fingerprint),
+            )],
+            ..base_request()
+        })
+        .unwrap();
+
+        // The repaired event is still created, for everyone
+        assert_eq!(response.created.len(), 1);
+        assert_eq!(
+            response.created[0].summary,
+            "Introduction to Clinical Pharmacology, 200-299"
+        );
+        assert_eq!(
+            response.diagnostics,
+            vec![
+                Diagnostic {
+                    event: bad_time,
+                    reason: "Could not parse field 'ET' with value '25:00'".to_owned(),
+                    action: Action::Skipped,
+                },
+                Diagnostic {
+                    event: backwards,
+                    reason: "End time '2017-11-12T13:00:00+00:00' is before start time '2017-11-12T14:03:00+00:00'".to_owned(),
+                    action: Action::Skipped,
+                },
+                Diagnostic {
+                    event: bad_groups,
+                    reason: "Could not parse groups '253, spam': unexpected 'spam' at byte 5"
+                        .to_owned(),
+                    action: Action::AssignedAllGroups,
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_universe() {
+        // Events for everyone are assigned to the given universe
+        let request = CalendarUpdateRequest {
+            new: vec![keats::Event {
+                groups: None,
+                ..BASE_KEATS_EVENT.clone()
+            }],
+            groups: vec![Group::from(3).into()],
+            universe: keats::groups_parser::GroupUniverse { start: 1, end: 4 },
+            ..base_request()
+        };
+        assert_eq!(
+            calculate_calendar_update(request.clone())
+                .unwrap()
+                .created
+                .len(),
+            1
+        );
+        assert_eq!(
+            calculate_calendar_update(CalendarUpdateRequest {
+                groups: vec![Group::from(253).into()],
+                ..request
+            })
+            .unwrap()
+            .created
+            .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_window() {
+        let event_on = |date: &str| keats::Event {
+            date: format!("{}T00:00:00", date),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let request = CalendarUpdateRequest {
+            new: vec![
+                event_on("2017-11-05"),
+                event_on("2017-11-12"),
+                event_on("2017-11-19"),
+                event_on("2017-11-26"),
+            ],
+            time_min: DateTime::parse_from_rfc3339("2017-11-06T00:00:00+00:00").unwrap(),
+            ..base_request()
+        };
+        let starts = |request: CalendarUpdateRequest| {
+            calculate_calendar_update(request)
+                .unwrap()
+                .created
+                .into_iter()
+                .map(|event| event.start.datetime.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Unbounded
+        assert_eq!(
+            starts(request.clone()),
+            vec![
+                "2017-11-12T14:03:00+00:00",
+                "2017-11-19T14:03:00+00:00",
+                "2017-11-26T14:03:00+00:00",
+            ]
+        );
+
+        // Events starting exactly at the end are left out, like the Google API
+        let time_max = DateTime::parse_from_rfc3339("2017-11-19T14:03:00+00:00").unwrap();
+        assert_eq!(
+            starts(CalendarUpdateRequest {
+                time_max: Some(time_max),
+                ..request.clone()
+            }),
+            vec!["2017-11-12T14:03:00+00:00"]
+        );
+
+        // Rolling weeks from time_min
+        let two_weeks = CalendarUpdateRequest {
+            weeks: Some(2),
+            ..request.clone()
+        };
+        assert_eq!(
+            two_weeks.window_end(),
+            Some(DateTime::parse_from_rfc3339("2017-11-20T00:00:00+00:00").unwrap())
+        );
+        assert_eq!(
+            starts(two_weeks.clone()),
+            vec!["2017-11-12T14:03:00+00:00", "2017-11-19T14:03:00+00:00"]
+        );
+
+        // The earlier bound applies
+        assert_eq!(
+            CalendarUpdateRequest {
+                time_max: Some(time_max),
+                ..two_weeks.clone()
             }
+            .window_end(),
+            Some(time_max)
+        );
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_group_assignments() {
+        let event_on = |date: &str, groups: &str| keats::Event {
+            date: format!("{}T00:00:00", date),
+            groups: Some(groups.to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let request = CalendarUpdateRequest {
+            new: vec![
+                event_on("2017-11-12", "201"),
+                event_on("2017-11-13", "201"),
+                event_on("2017-11-13", "301"),
+                event_on("2017-11-14", "301"),
+                event_on("2017-11-14", "A1"),
+            ],
+            // Switched from 201 to 301 on the 13th, and always in tutorial group A1
+            groups: serde_json::from_str(
+                r#"[
+{"group": 201, "until": "2017-11-12"},
+{"group": "301", "from": "2017-11-13"},
+"A1"
+]"#,
+            )
+            .unwrap(),
+            ..base_request()
+        };
+        assert_eq!(
+            request.groups[0],
+            GroupAssignment {
+                group: Group::from(201),
+                from: None,
+                until: Some(NaiveDate::from_ymd(2017, 11, 12)),
+            }
+        );
+
+        let created = calculate_calendar_update(request).unwrap().created;
+        assert_eq!(
+            created
+                .iter()
+                .map(|event| (
+                    event.start.datetime.as_deref().unwrap(),
+                    event.summary.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "2017-11-12T14:03:00+00:00",
+                    "Introduction to Clinical Pharmacology, 201"
+                ),
+                (
+                    "2017-11-13T14:03:00+00:00",
+                    "Introduction to Clinical Pharmacology, 301"
+                ),
+                (
+                    "2017-11-14T14:03:00+00:00",
+                    "Introduction to Clinical Pharmacology, 301"
+                ),
+                (
+                    "2017-11-14T14:03:00+00:00",
+                    "Introduction to Clinical Pharmacology, A1"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_assignments_in_summer_time() {
+        // Just after midnight in London is still the day before in UTC
+        let event = |groups: &str| keats::Event {
+            date: "2018-06-02T00:00:00".to_owned(),
+            start_time: "00:30".to_owned(),
+            end_time: "01:30".to_owned(),
+            groups: Some(groups.to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let groups: Vec<GroupAssignment> = serde_json::from_str(
+            r#"[{"group": 201, "until": "2018-06-01"}, {"group": 301, "from": "2018-06-02"}]"#,
         )
+        .unwrap();
+        let new_group = Event::try_from(event("301")).unwrap();
+        assert_eq!(
+            new_group.inner.start,
+            DateTime::parse_from_rfc3339("2018-06-01T23:30:00+00:00").unwrap()
+        );
+        assert!(new_group.is_assigned(&groups));
+        assert!(!Event::try_from(event("201")).unwrap().is_assigned(&groups));
+    }
+
+    #[test]
+    fn test_calculate_calendar_update_parallel_sessions() {
+        // Sessions only differing by room share an identity, so are numbered
+        let parallel = |room: &str| keats::Event {
+            room: Some(room.to_owned()),
+            ..BASE_KEATS_EVENT.clone()
+        };
+        let request = CalendarUpdateRequest {
+            new: vec![
+                BASE_KEATS_EVENT.clone(),
+                parallel("Room 4"),
+                BASE_KEATS_EVENT.clone(),
+            ],
+            ..base_request()
+        };
+        let response = calculate_calendar_update(request.clone()).unwrap();
+        let ids: Vec<String> = response.created.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(ids, vec!["oa239fa9vmoeg", "np9hh5pgujuoc"]);
+
+        // A parallel session moving room keeps its id, so is patched in place
+        let existing = response.created.iter().map(listed).collect();
+        let response = calculate_calendar_update(CalendarUpdateRequest {
+            new: vec![BASE_KEATS_EVENT.clone(), parallel("Room 5")],
+            existing,
+            ..request
+        })
+        .unwrap();
+        assert_eq!(response.created, vec![]);
+        assert_eq!(response.deleted, Vec::<String>::new());
+        assert_eq!(
+            response
+                .updated
+                .iter()
+                .map(|e| (e.id.as_str(), e.location.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(ids[1].as_str(), "Room 5, Unseen University")]
+        );
     }
 }
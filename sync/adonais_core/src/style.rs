@@ -0,0 +1,250 @@
+use crate::hash::Encode;
+use crate::{google, EventInner};
+
+/// A user preference styling matching events, so that exams or practicals stand out.
+///
+/// A rule matches events whose type and module code match its patterns, where `*` matches any text
+/// and case is ignored. A rule without patterns matches every event. In JSON, rules look like
+/// `{"type": "Exam", "color_id": "11", "reminders": [1440, 60], "transparency": "opaque"}`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct StyleRule {
+    /// Pattern for the event type, e.g. `Practical*`.
+    #[serde(rename = "type", default)]
+    pub type_: Option<String>,
+    /// Pattern for the module code, e.g. `MBBS2*`.
+    #[serde(default)]
+    pub code: Option<String>,
+    /// One of the Google event colours, e.g. `"11"` for red.
+    #[serde(default)]
+    pub color_id: Option<String>,
+    /// Popup reminders, in minutes before the event. An empty list turns reminders off.
+    #[serde(default)]
+    pub reminders: Option<Vec<u32>>,
+    /// Whether the event shows as busy (`opaque`) or free (`transparent`).
+    #[serde(default)]
+    pub transparency: Option<google::Transparency>,
+}
+
+/// The settings applied to an event by every rule matching it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub color_id: Option<String>,
+    pub reminders: Option<Vec<u32>>,
+    pub transparency: Option<google::Transparency>,
+}
+
+impl Encode for Style {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let transparency = self.transparency.map(|transparency| match transparency {
+            google::Transparency::Opaque => "opaque",
+            google::Transparency::Transparent => "transparent",
+        });
+        (&self.color_id, &self.reminders, transparency).encode(bytes);
+    }
+}
+
+/// Whether `text` matches a pattern where `*` stands for any text, ignoring case.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the text after the last `*` stops matching
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    t = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn matches_field(pattern: &Option<String>, field: Option<&str>) -> bool {
+    match pattern {
+        Some(pattern) => field.is_some_and(|field| matches_pattern(pattern, field)),
+        None => true,
+    }
+}
+
+impl StyleRule {
+    pub fn matches(&self, event: &EventInner) -> bool {
+        matches_field(&self.type_, event.type_.as_deref())
+            && matches_field(&self.code, Some(&event.code))
+    }
+}
+
+impl Style {
+    /// Apply every matching rule in order, so later rules override what earlier ones set.
+    pub fn from_rules(rules: &[StyleRule], event: &EventInner) -> Self {
+        let mut style = Style::default();
+        for rule in rules.iter().filter(|rule| rule.matches(event)) {
+            if rule.color_id.is_some() {
+                style.color_id = rule.color_id.clone();
+            }
+            if rule.reminders.is_some() {
+                style.reminders = rule.reminders.clone();
+            }
+            if rule.transparency.is_some() {
+                style.transparency = rule.transparency;
+            }
+        }
+        style
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Style::default()
+    }
+
+    /// Set the styled fields of a Google event.
+    ///
+    /// Fields no rule set are reset to the calendar's defaults, rather than left out,
+    /// so that patching an event clears the style it had before.
+    pub fn apply(&self, event: &mut google::Event) {
+        event.color_id = self.color_id.clone();
+        event.reminders = Some(match &self.reminders {
+            Some(minutes) => google::Reminders {
+                use_default: false,
+                overrides: minutes
+                    .iter()
+                    .map(|&minutes| google::Reminder {
+                        method: google::ReminderMethod::Popup,
+                        minutes,
+                    })
+                    .collect(),
+            },
+            None => google::Reminders {
+                use_default: true,
+                overrides: vec![],
+            },
+        });
+        event.transparency = Some(self.transparency.unwrap_or(google::Transparency::Opaque));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::{keats, Event};
+
+    fn event(type_: &str, code: &str) -> EventInner {
+        Event::try_from(keats::Event {
+            date: "2017-11-12T00:00:00".to_owned(),
+            start_time: "14:03".to_owned(),
+            end_time: "15:00".to_owned(),
+            code: code.to_owned(),
+            groups: None,
+            title: None,
+            type_: Some(type_.to_owned()),
+            staff: None,
+            room: None,
+            campus: None,
+        })
+        .unwrap()
+        .inner
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("Exam", "exam"));
+        assert!(!matches_pattern("Exam", "Exams"));
+        assert!(matches_pattern("Practical*", "Practical - Anatomy"));
+        assert!(matches_pattern("*pract*", "Clinical Practical"));
+        assert!(matches_pattern("MBBS*2", "MBBS0202"));
+        assert!(!matches_pattern("MBBS*2", "MBBS0201"));
+        assert!(matches_pattern("*", ""));
+        assert!(!matches_pattern("", "MBBS0001"));
+    }
+
+    #[test]
+    fn test_style_from_rules() {
+        let rules: Vec<StyleRule> = serde_json::from_str(
+            r#"[
+                {"reminders": [10]},
+                {"type": "Exam", "color_id": "11", "reminders": [1440, 60], "transparency": "opaque"},
+                {"type": "Practical*", "color_id": "2"},
+                {"code": "MBBS2*", "type": "Lecture", "transparency": "transparent", "reminders": []}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Style::from_rules(&rules, &event("exam", "MBBS0001")),
+            Style {
+                color_id: Some("11".to_owned()),
+                reminders: Some(vec![1440, 60]),
+                transparency: Some(google::Transparency::Opaque),
+            }
+        );
+        assert_eq!(
+            Style::from_rules(&rules, &event("Practical - Anatomy", "MBBS0001")),
+            Style {
+                color_id: Some("2".to_owned()),
+                reminders: Some(vec![10]),
+                transparency: None,
+            }
+        );
+        assert_eq!(
+            Style::from_rules(&rules, &event("Lecture", "MBBS2001")),
+            Style {
+                color_id: None,
+                reminders: Some(vec![]),
+                transparency: Some(google::Transparency::Transparent),
+            }
+        );
+        assert!(Style::from_rules(&[], &event("Lecture", "MBBS2001")).is_empty());
+    }
+
+    #[test]
+    fn test_style_apply() {
+        let mut event = google::Event::default();
+        Style {
+            color_id: Some("11".to_owned()),
+            reminders: Some(vec![60]),
+            transparency: Some(google::Transparency::Opaque),
+        }
+        .apply(&mut event);
+        assert_eq!(event.color_id, Some("11".to_owned()));
+        assert_eq!(
+            event.reminders,
+            Some(google::Reminders {
+                use_default: false,
+                overrides: vec![google::Reminder {
+                    method: google::ReminderMethod::Popup,
+                    minutes: 60,
+                }],
+            })
+        );
+        assert_eq!(event.transparency, Some(google::Transparency::Opaque));
+
+        // Without any rules, every styled field is reset
+        Style::default().apply(&mut event);
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["colorId"],
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            event.reminders,
+            Some(google::Reminders {
+                use_default: true,
+                overrides: vec![],
+            })
+        );
+        assert_eq!(event.transparency, Some(google::Transparency::Opaque));
+    }
+}
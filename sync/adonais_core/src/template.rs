@@ -0,0 +1,428 @@
+//! Templates for the text of generated events.
+//!
+//! A template is text with placeholders for fields of the event, such as `[{type}] {title|code}`:
+//!
+//! - `{field}` is replaced with the field, or nothing if the event doesn't have it
+//! - `{title|code}` falls back to later fields if earlier ones are missing
+//! - `{?...}` is an optional section, left out unless every placeholder in it has a value
+//! - `{?...|...}` picks the first alternative with every placeholder filled in
+//! - `\` escapes the next character, e.g. `\{`
+//! - anything else, including `[` and `]`, is written as is
+//!
+//! The fields are `code`, `title`, `type`, `staff`, `room`, `campus`, `groups` (normalised,
+//! and missing if KEATS gave none, but every group of the cohort if they could not be read),
+//! `groups_raw` (as written in KEATS), `date` (`yyyy-mm-dd`), `start` and `end` (`hh:mm`).
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset};
+use chrono_tz::{Europe::London, Tz};
+
+use crate::keats::groups_parser::format_groups;
+use crate::EventInner;
+
+fn local(datetime: &DateTime<FixedOffset>) -> DateTime<Tz> {
+    datetime.with_timezone(&London)
+}
+
+/// A field of the event that a placeholder stands for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Code,
+    Title,
+    Type,
+    Staff,
+    Room,
+    Campus,
+    Groups,
+    GroupsRaw,
+    Date,
+    Start,
+    End,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "code" => Field::Code,
+            "title" => Field::Title,
+            "type" => Field::Type,
+            "staff" => Field::Staff,
+            "room" => Field::Room,
+            "campus" => Field::Campus,
+            "groups" => Field::Groups,
+            "groups_raw" => Field::GroupsRaw,
+            "date" => Field::Date,
+            "start" => Field::Start,
+            "end" => Field::End,
+            _ => return None,
+        })
+    }
+
+    fn value(self, event: &EventInner) -> Option<String> {
+        match self {
+            Field::Code => Some(event.code.clone()),
+            Field::Title => event.title.clone(),
+            Field::Type => event.type_.clone(),
+            Field::Staff => event.staff.clone(),
+            Field::Room => event.room.clone(),
+            Field::Campus => event.campus.clone(),
+            // Only shown if KEATS gave groups, so events repaired to be for everyone show the cohort
+            Field::Groups => match event.groups_raw {
+                Some(ref groups_raw) if !groups_raw.is_empty() => {
+                    Some(format_groups(&event.groups))
+                }
+                _ => None,
+            },
+            Field::GroupsRaw => event.groups_raw.clone(),
+            Field::Date => Some(local(&event.start).format("%Y-%m-%d").to_string()),
+            Field::Start => Some(local(&event.start).format("%H:%M").to_string()),
+            Field::End => Some(local(&event.end).format("%H:%M").to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    /// The first of these fields that the event has.
+    Placeholder(Vec<Field>),
+    /// The first alternative with every placeholder filled in, if any.
+    Optional(Vec<Vec<Segment>>),
+}
+
+/// Where, and why, parsing a template failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemplateError {
+    /// Byte offset of the problem in the template.
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {} of template", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+struct Parser<'a> {
+    input: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, offset: usize, reason: &str) -> TemplateError {
+        TemplateError {
+            offset,
+            reason: reason.to_owned(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.offset..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        Some(c)
+    }
+
+    /// Parse segments until the end of input, or the end of an optional section if `nested`.
+    fn segments(&mut self, nested: bool) -> Result<Vec<Segment>, TemplateError> {
+        let mut segments = vec![];
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '}' | '|' if nested => break,
+                '\\' => {
+                    let start = self.offset;
+                    self.next();
+                    match self.next() {
+                        Some(escaped) => text.push(escaped),
+                        None => return Err(self.error(start, "Nothing to escape after '\\'")),
+                    }
+                }
+                '{' => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(if self.input[self.offset..].starts_with("{?") {
+                        self.optional()?
+                    } else {
+                        self.placeholder()?
+                    });
+                }
+                '}' => return Err(self.error(self.offset, "Unmatched '}'")),
+                _ => {
+                    text.push(c);
+                    self.next();
+                }
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(segments)
+    }
+
+    fn placeholder(&mut self) -> Result<Segment, TemplateError> {
+        let start = self.offset;
+        self.next();
+        let length = match self.input[self.offset..].find('}') {
+            Some(length) => length,
+            None => return Err(self.error(start, "Unclosed '{'")),
+        };
+        let mut fields = vec![];
+        let mut offset = self.offset;
+        for name in self.input[self.offset..self.offset + length].split('|') {
+            match Field::from_name(name.trim()) {
+                Some(field) => fields.push(field),
+                None => return Err(self.error(offset, &format!("Unknown field '{}'", name.trim()))),
+            }
+            offset += name.len() + 1;
+        }
+        self.offset += length + 1;
+        Ok(Segment::Placeholder(fields))
+    }
+
+    fn optional(&mut self) -> Result<Segment, TemplateError> {
+        let start = self.offset;
+        self.offset += "{?".len();
+        let mut alternatives = vec![self.segments(true)?];
+        loop {
+            match self.next() {
+                Some('|') => alternatives.push(self.segments(true)?),
+                Some('}') => return Ok(Segment::Optional(alternatives)),
+                _ => return Err(self.error(start, "Unclosed '{?'")),
+            }
+        }
+    }
+}
+
+/// Render segments, and whether every placeholder had a value.
+fn render(segments: &[Segment], event: &EventInner) -> (String, bool) {
+    let mut output = String::new();
+    let mut complete = true;
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => output.push_str(text),
+            Segment::Placeholder(fields) => {
+                match fields.iter().find_map(|field| field.value(event)) {
+                    Some(value) => output.push_str(&value),
+                    None => complete = false,
+                }
+            }
+            Segment::Optional(alternatives) => {
+                if let Some(text) = alternatives
+                    .iter()
+                    .map(|alternative| render(alternative, event))
+                    .find_map(|(text, complete)| if complete { Some(text) } else { None })
+                {
+                    output.push_str(&text);
+                }
+            }
+        }
+    }
+    (output, complete)
+}
+
+/// A parsed template, deserialized from its source text.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Fill in the template from an event. Missing fields outside optional sections are left empty.
+    pub fn render(&self, event: &EventInner) -> String {
+        render(&self.segments, event).0
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let segments = Parser {
+            input: source,
+            offset: 0,
+        }
+        .segments(false)?;
+        Ok(Template {
+            source: source.to_owned(),
+            segments,
+        })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = TemplateError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        source.parse()
+    }
+}
+
+/// The templates for each field of a generated event, set per user.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    pub summary: Template,
+    pub description: Template,
+    pub location: Template,
+}
+
+impl Default for Templates {
+    /// Title and groups, then the code, staff and type on separate lines, then the room and campus.
+    fn default() -> Self {
+        Templates {
+            summary: "{title|code}{?, {groups}}".parse().unwrap(),
+            description: "{code}{?\n{staff}}{?\n{type}}".parse().unwrap(),
+            location: "{?{room}, {campus}|{room|campus}}".parse().unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keats;
+    use crate::Event;
+
+    fn event(edit: impl FnOnce(&mut keats::Event)) -> EventInner {
+        let mut event = keats::Event {
+            date: "2017-11-12T00:00:00".to_owned(),
+            start_time: "14:03".to_owned(),
+            end_time: "15:00".to_owned(),
+            code: "MBBS0001".to_owned(),
+            groups: Some("253, 254-256".to_owned()),
+            title: Some("Pharmacology".to_owned()),
+            type_: Some("Lecture".to_owned()),
+            staff: Some("John Keats".to_owned()),
+            room: Some("Room 3b".to_owned()),
+            campus: Some("Guy's".to_owned()),
+        };
+        edit(&mut event);
+        Event::try_from(event).unwrap().inner
+    }
+
+    fn render(template: &str, event: &EventInner) -> String {
+        template.parse::<Template>().unwrap().render(event)
+    }
+
+    #[test]
+    fn test_render_fields() {
+        let lecture = event(|_| {});
+        assert_eq!(
+            render(
+                "{code}|{title}|{type}|{staff}|{room}|{campus}|{groups}|{groups_raw}|{date} {start}-{end}",
+                &lecture
+            ),
+            "MBBS0001|Pharmacology|Lecture|John Keats|Room 3b|Guy's|253-256|253, 254-256|2017-11-12 14:03-15:00"
+        );
+        // Brackets are only text, as in the summaries some cohorts want
+        assert_eq!(
+            render("[{type}] {title}", &lecture),
+            "[Lecture] Pharmacology"
+        );
+        assert_eq!(render("]{title}[", &lecture), "]Pharmacology[");
+        let summer = event(|event| event.date = "2018-06-01T00:00:00".to_owned());
+        assert_eq!(
+            render("{date} {start}-{end}", &summer),
+            "2018-06-01 14:03-15:00"
+        );
+        assert_eq!(
+            render("\\{{type}\\} {title}", &lecture),
+            "{Lecture} Pharmacology"
+        );
+        assert_eq!(render("{ staff | code }", &lecture), "John Keats");
+    }
+
+    #[test]
+    fn test_render_missing_fields() {
+        let untitled = event(|event| {
+            event.title = None;
+            event.groups = None;
+            event.room = None;
+        });
+        assert_eq!(render("{title|code}", &untitled), "MBBS0001");
+        assert_eq!(render("{title}: {type}", &untitled), ": Lecture");
+        assert_eq!(render("{type}{? for {groups}}", &untitled), "Lecture");
+        assert_eq!(
+            render("{?{title}|{code} {type}}", &untitled),
+            "MBBS0001 Lecture"
+        );
+        assert_eq!(render("{?{title}|{room}}", &untitled), "");
+        assert_eq!(
+            render("{?[{type}] }{code}", &untitled),
+            "[Lecture] MBBS0001"
+        );
+        // Nested sections are optional within their section
+        assert_eq!(
+            render("{?{type}{? with {staff}}{? in {room}}}", &untitled),
+            "Lecture with John Keats"
+        );
+    }
+
+    #[test]
+    fn test_default_templates() {
+        let templates = Templates::default();
+        let lecture = event(|_| {});
+        assert_eq!(templates.summary.render(&lecture), "Pharmacology, 253-256");
+        assert_eq!(
+            templates.description.render(&lecture),
+            "MBBS0001\nJohn Keats\nLecture"
+        );
+        assert_eq!(templates.location.render(&lecture), "Room 3b, Guy's");
+
+        let sparse = event(|event| {
+            event.title = None;
+            event.groups = Some("".to_owned());
+            event.staff = None;
+            event.room = None;
+        });
+        assert_eq!(templates.summary.render(&sparse), "MBBS0001");
+        assert_eq!(templates.description.render(&sparse), "MBBS0001\nLecture");
+        assert_eq!(templates.location.render(&sparse), "Guy's");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |template: &str| template.parse::<Template>().unwrap_err();
+        assert_eq!(
+            error("{title} {spam}"),
+            TemplateError {
+                offset: 9,
+                reason: "Unknown field 'spam'".to_owned()
+            }
+        );
+        assert_eq!(error("{title|spam}").offset, 7);
+        assert_eq!(error("{?{title}").reason, "Unclosed '{?'");
+        assert_eq!(error("{title").reason, "Unclosed '{'");
+        assert_eq!(error("title}").offset, 5);
+        assert_eq!(error("title}").reason, "Unmatched '}'");
+        assert_eq!(error("title\\").offset, 5);
+    }
+
+    #[test]
+    fn test_templates_from_json() {
+        let templates: Templates =
+            serde_json::from_str(r#"{"summary": "[{type}] {title|code}"}"#).unwrap();
+        assert_eq!(templates.summary.as_str(), "[{type}] {title|code}");
+        assert_eq!(templates.location, Templates::default().location);
+
+        let error = serde_json::from_str::<Templates>(r#"{"summary": "{spam}"}"#).unwrap_err();
+        assert!(error.to_string().contains("Unknown field 'spam'"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, FixedOffset};
+
+use adonais_core::ical::{self, IcalRequest, Sequences};
+use adonais_core::keats::client;
+use adonais_core::keats::groups_parser::{parse_group_range, Group, GroupUniverse};
+
+/// Everything that can go wrong serving a feed.
+#[derive(Debug)]
+pub enum Error {
+    /// The KEATS file or state file could not be read or written.
+    Io(PathBuf, io::Error),
+    /// The KEATS file or state file is not valid JSON.
+    Json(PathBuf, serde_json::Error),
+    /// No KEATS event is for the group, so it is likely a typo or from another cohort.
+    UnknownGroup(Group),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(f, "Could not access '{}': {}", path.display(), error),
+            Error::Json(path, error) => {
+                write!(f, "Invalid JSON in '{}': {}", path.display(), error)
+            }
+            Error::UnknownGroup(group) => write!(f, "No events for group {}", group),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, error) => Some(error),
+            Error::Json(_, error) => Some(error),
+            Error::UnknownGroup(_) => None,
+        }
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &PathBuf) -> Result<T, Error> {
+    let contents = fs::read_to_string(path).map_err(|error| Error::Io(path.clone(), error))?;
+    serde_json::from_str(&contents).map_err(|error| Error::Json(path.clone(), error))
+}
+
+/// Timetables for each group, rendered from a KEATS export.
+pub struct Feed {
+    /// KEATS events as JSON, read for every request so that updates are served without restarting.
+    source: PathBuf,
+    /// Where to keep sequence numbers between restarts, if anywhere.
+    state: Option<PathBuf>,
+    universe: GroupUniverse,
+    weeks: u32,
+    /// Sequence numbers for each group's feed.
+    sequences: Mutex<HashMap<String, Sequences>>,
+}
+
+impl Feed {
+    /// A feed of `weeks` of events for the cohort `universe`, with sequence numbers loaded from `state` if it exists.
+    pub fn new(
+        source: PathBuf,
+        state: Option<PathBuf>,
+        universe: GroupUniverse,
+        weeks: u32,
+    ) -> Result<Self, Error> {
+        let sequences = match &state {
+            Some(state) if state.exists() => read_json(state)?,
+            _ => HashMap::new(),
+        };
+        Ok(Feed {
+            source,
+            state,
+            universe,
+            weeks,
+            sequences: Mutex::new(sequences),
+        })
+    }
+
+    /// Render the calendar for a group, for the window starting at `time_min`.
+    ///
+    /// Groups that no event is for at any time, such as those outside the universe, are an `UnknownGroup` error,
+    /// rather than an empty calendar that would never fill up.
+    pub fn render(
+        &self,
+        group: &Group,
+        time_min: DateTime<FixedOffset>,
+        stamp: &DateTime<chrono::Utc>,
+    ) -> Result<String, Error> {
+        // A row that is not a KEATS event only loses that event, rather than every feed
+        let (new, unreadable) = client::decode_rows(read_json(&self.source)?);
+        for row in unreadable.iter() {
+            eprintln!("KEATS row {} is unreadable: {}", row.index, row.error);
+        }
+        let known = new.iter().any(|event| {
+            parse_group_range(event.groups.as_deref().unwrap_or_default(), &self.universe)
+                .contains(group)
+        });
+        if !known {
+            return Err(Error::UnknownGroup(group.clone()));
+        }
+        let key = group.to_string();
+
+        let mut sequences = self.sequences.lock().unwrap();
+        let request = IcalRequest {
+            new,
+            groups: vec![group.clone().into()],
+            time_min,
+            time_max: None,
+            weeks: Some(self.weeks),
+            universe: self.universe.clone(),
+            filter: Default::default(),
+            options: ical::Options {
+                name: Some(format!("Group {}", group)),
+                ..Default::default()
+            },
+            sequences: sequences.get(&key).cloned().unwrap_or_default(),
+        };
+        let response = ical::calculate_ical(request, stamp);
+        for diagnostic in response.diagnostics.iter() {
+            eprintln!(
+                "KEATS event {} on {}: {}",
+                diagnostic.event.code, diagnostic.event.date, diagnostic.reason
+            );
+        }
+
+        if sequences.get(&key) != Some(&response.sequences) {
+            sequences.insert(key, response.sequences);
+            if let Some(state) = &self.state {
+                let json = serde_json::to_string(&*sequences)
+                    .map_err(|error| Error::Json(state.clone(), error))?;
+                fs::write(state, json).map_err(|error| Error::Io(state.clone(), error))?;
+            }
+        }
+        Ok(response.ics)
+    }
+}
+
+/// The group of a feed path like `/feed/253.ics`, ignoring any query.
+pub fn route(url: &str) -> Option<Group> {
+    let path = url.split('?').next().unwrap_or_default();
+    let name = path.strip_prefix("/feed/")?.strip_suffix(".ics")?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(Group::from(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/keats.json")
+    }
+
+    fn time_min() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2019-09-02T00:00:00+01:00").unwrap()
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("/feed/253.ics"), Some(Group::from(253)));
+        assert_eq!(route("/feed/A1.ics?weeks=2"), Some(Group::from("A1")));
+        assert_eq!(route("/feed/.ics"), None);
+        assert_eq!(route("/feed/253"), None);
+        assert_eq!(route("/feed/../253.ics"), None);
+        assert_eq!(route("/other/253.ics"), None);
+    }
+
+    #[test]
+    fn test_render_fixture() {
+        let feed = Feed::new(fixture(), None, GroupUniverse::default(), 2).unwrap();
+        let stamp = Utc.ymd(2019, 9, 1).and_hms(0, 0, 0);
+
+        let ics = feed.render(&Group::from(253), time_min(), &stamp).unwrap();
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.contains("X-WR-CALNAME:Group 253\r\n"));
+        // The exam has an invalid time, so is skipped
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 3);
+        assert!(ics.contains("SUMMARY:Anatomy practical\\, 253-256\r\n"));
+
+        let ics = feed.render(&Group::from(201), time_min(), &stamp).unwrap();
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 3);
+        assert!(ics.contains("SUMMARY:Anatomy practical\\, 201-204\r\n"));
+
+        // Events for everyone are only for the cohort, so other groups have no feed
+        match feed.render(&Group::from(999), time_min(), &stamp) {
+            Err(Error::UnknownGroup(group)) => assert_eq!(group, Group::from(999)),
+            other => panic!("Expected an unknown group, got {:?}", other.map(|_| ())),
+        }
+        let feed = Feed::new(
+            fixture(),
+            None,
+            GroupUniverse {
+                start: 900,
+                end: 999,
+            },
+            2,
+        )
+        .unwrap();
+        let ics = feed.render(&Group::from(999), time_min(), &stamp).unwrap();
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+
+        // As do groups with no events at all, even in the cohort
+        let feed = Feed::new(fixture(), None, GroupUniverse { start: 1, end: 4 }, 2).unwrap();
+        assert!(feed.render(&Group::from(253), time_min(), &stamp).is_ok());
+        assert!(feed.render(&Group::from(4), time_min(), &stamp).is_ok());
+        assert!(feed.render(&Group::from(5), time_min(), &stamp).is_err());
+    }
+
+    #[test]
+    fn test_render_unreadable_row() {
+        let directory =
+            std::env::temp_dir().join(format!("adonais_feed_rows_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("keats.json");
+        let mut rows: Vec<serde_json::Value> = read_json(&fixture()).unwrap();
+        rows.push(serde_json::json!({"C": 5, "Date": null}));
+        fs::write(&source, serde_json::to_string(&rows).unwrap()).unwrap();
+        let stamp = Utc.ymd(2019, 9, 1).and_hms(0, 0, 0);
+
+        // The other events are still served
+        let feed = Feed::new(source, None, GroupUniverse::default(), 2).unwrap();
+        let ics = feed.render(&Group::from(253), time_min(), &stamp).unwrap();
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 3);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_state() {
+        let directory = std::env::temp_dir().join(format!("adonais_feed_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("keats.json");
+        let state = directory.join("state.json");
+        fs::copy(fixture(), &source).unwrap();
+        let stamp = Utc.ymd(2019, 9, 1).and_hms(0, 0, 0);
+
+        let feed = Feed::new(
+            source.clone(),
+            Some(state.clone()),
+            GroupUniverse::default(),
+            2,
+        )
+        .unwrap();
+        let ics = feed.render(&Group::from(253), time_min(), &stamp).unwrap();
+        assert!(!ics.contains("SEQUENCE:1"));
+
+        // A change made while the server is down still bumps the sequence
+        drop(feed);
+        let moved = fs::read_to_string(fixture())
+            .unwrap()
+            .replace("Dissection Room", "Dissection Room 2");
+        fs::write(&source, moved).unwrap();
+        let feed = Feed::new(
+            source.clone(),
+            Some(state.clone()),
+            GroupUniverse::default(),
+            2,
+        )
+        .unwrap();
+        let ics = feed.render(&Group::from(253), time_min(), &stamp).unwrap();
+        assert_eq!(ics.matches("SEQUENCE:1\r\n").count(), 1);
+
+        fs::remove_file(&source).unwrap();
+        match feed.render(&Group::from(253), time_min(), &stamp) {
+            Err(Error::Io(path, _)) => assert_eq!(path, source),
+            other => panic!("Expected an IO error, got {:?}", other.map(|_| ())),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
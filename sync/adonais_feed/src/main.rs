@@ -0,0 +1,150 @@
+//! Serves timetables as iCalendar feeds, for students to subscribe to without granting any permissions.
+
+use std::path::PathBuf;
+
+use adonais_core::keats::groups_parser::GroupUniverse;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, Utc};
+use structopt::StructOpt;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+mod feed;
+
+use feed::Feed;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Serve KEATS timetables as iCalendar feeds at /feed/<group>.ics")]
+struct Options {
+    /// KEATS events as JSON, e.g. `fixtures/keats.json`. Read again for every request.
+    source: PathBuf,
+    /// Address to listen on.
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    bind: String,
+    /// How many weeks of events to serve.
+    #[structopt(long, default_value = "12")]
+    weeks: u32,
+    /// The groups of the cohort, who get events with no groups given. Other groups with no events get no feed.
+    #[structopt(long, default_value = "200-299")]
+    universe: GroupUniverse,
+    /// Start feeds on this date, rather than a week ago, e.g. to serve an old export.
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+    /// File keeping sequence numbers between restarts, so calendar apps pick up changes.
+    #[structopt(long)]
+    state: Option<PathBuf>,
+}
+
+/// The start of the window, as midnight on `from` or a week before `now`.
+fn time_min(from: Option<NaiveDate>, now: &DateTime<Utc>) -> DateTime<FixedOffset> {
+    let utc = FixedOffset::east(0);
+    match from {
+        Some(from) => DateTime::from_utc(from.and_hms(0, 0, 0), utc),
+        None => (*now - Duration::weeks(1)).with_timezone(&utc),
+    }
+}
+
+fn respond(
+    feed: &Feed,
+    from: Option<NaiveDate>,
+    request: &Request,
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    if request.method() != &Method::Get && request.method() != &Method::Head {
+        return Response::from_string("Method not allowed").with_status_code(405);
+    }
+    let not_found = || Response::from_string("Not found").with_status_code(404);
+    let group = match feed::route(request.url()) {
+        Some(group) => group,
+        None => return not_found(),
+    };
+    let now = Utc::now();
+    match feed.render(&group, time_min(from, &now), &now) {
+        Ok(ics) => Response::from_string(ics).with_header(
+            "Content-Type: text/calendar; charset=utf-8"
+                .parse::<Header>()
+                .unwrap(),
+        ),
+        Err(feed::Error::UnknownGroup(_)) => not_found(),
+        Err(error) => {
+            eprintln!("Failed to render feed for group {}: {}", group, error);
+            Response::from_string("Internal server error").with_status_code(500)
+        }
+    }
+}
+
+fn serve(server: Server, feed: &Feed, from: Option<NaiveDate>) {
+    for request in server.incoming_requests() {
+        let response = respond(feed, from, &request);
+        if let Err(error) = request.respond(response) {
+            eprintln!("Failed to send response: {}", error);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = Options::from_args();
+    let feed = Feed::new(
+        options.source,
+        options.state,
+        options.universe,
+        options.weeks,
+    )?;
+    let server = Server::http(&options.bind).map_err(|error| error.to_string())?;
+    println!("Serving feeds at http://{}/feed/<group>.ics", options.bind);
+    serve(server, &feed, options.from);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use super::*;
+
+    fn get(address: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, address
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_time_min() {
+        let now = DateTime::parse_from_rfc3339("2019-09-09T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            time_min(None, &now).to_rfc3339(),
+            "2019-09-02T12:00:00+00:00"
+        );
+        assert_eq!(
+            time_min(Some(NaiveDate::from_ymd(2019, 9, 1)), &now).to_rfc3339(),
+            "2019-09-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_serve_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/keats.json");
+        let feed = Feed::new(fixture, None, GroupUniverse::default(), 2).unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_string();
+        thread::spawn(move || serve(server, &feed, Some(NaiveDate::from_ymd(2019, 9, 2))));
+
+        let response = get(&address, "/feed/253.ics");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Content-Type: text/calendar; charset=utf-8\r\n"));
+        assert!(response.contains("\r\n\r\nBEGIN:VCALENDAR\r\n"));
+        assert_eq!(response.matches("BEGIN:VEVENT").count(), 3);
+
+        assert!(get(&address, "/feed/253").starts_with("HTTP/1.1 404"));
+        assert!(get(&address, "/feed/999.ics").starts_with("HTTP/1.1 404"));
+        assert!(get(&address, "/").starts_with("HTTP/1.1 404"));
+    }
+}
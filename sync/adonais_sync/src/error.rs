@@ -0,0 +1,109 @@
+use std::fmt;
+use std::io;
+
+use adonais_core::keats::client;
+
+/// Everything that can go wrong running a command.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A request could not be sent, or its response read.
+    Http(reqwest::Error),
+    /// A server responded with an error.
+    Status {
+        url: String,
+        status: u16,
+        body: String,
+    },
+    Json(serde_json::Error),
+    Csv(csv::Error),
+    /// KEATS events could not be fetched or decoded.
+    Keats(client::Error),
+    /// The calendar update could not be calculated.
+    Core(adonais_core::Error),
+    /// An argument was invalid.
+    Usage(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "IO error: {}", error),
+            Error::Http(error) => write!(f, "HTTP error: {}", error),
+            Error::Status { url, status, body } => {
+                write!(f, "Request to '{}' failed with {}: {}", url, status, body)
+            }
+            Error::Json(error) => write!(f, "JSON error: {}", error),
+            Error::Csv(error) => write!(f, "CSV error: {}", error),
+            Error::Keats(error) => write!(f, "{}", error),
+            Error::Core(error) => write!(f, "{}", error),
+            Error::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Http(error) => Some(error),
+            Error::Json(error) => Some(error),
+            Error::Csv(error) => Some(error),
+            Error::Keats(error) => Some(error),
+            Error::Core(error) => Some(error),
+            Error::Status { .. } | Error::Usage(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(error: csv::Error) -> Self {
+        Error::Csv(error)
+    }
+}
+
+impl From<client::Error> for Error {
+    fn from(error: client::Error) -> Self {
+        Error::Keats(error)
+    }
+}
+
+impl From<adonais_core::Error> for Error {
+    fn from(error: adonais_core::Error) -> Self {
+        Error::Core(error)
+    }
+}
+
+/// Turn an unsuccessful response into an error, keeping the body to explain it.
+pub fn check_status(
+    response: reqwest::blocking::Response,
+) -> Result<reqwest::blocking::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().to_string();
+    Err(Error::Status {
+        url,
+        status: status.as_u16(),
+        body: response.text().unwrap_or_default(),
+    })
+}
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Europe::London;
+
+use adonais_core::ical::{self, Sequences};
+use adonais_core::keats::groups_parser::format_groups;
+use adonais_core::Event;
+
+use crate::error::Error;
+
+/// Formats the selected events can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// An iCalendar file, to import into other calendars.
+    Ics,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "ics" => Ok(Format::Ics),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::Usage(format!(
+                "Unknown format '{}', expected one of ics, json or csv",
+                format
+            ))),
+        }
+    }
+}
+
+/// An event as a flat record, with local times.
+#[derive(Debug, PartialEq, Serialize)]
+struct Row<'a> {
+    id: &'a str,
+    date: String,
+    start: String,
+    end: String,
+    code: &'a str,
+    title: Option<&'a str>,
+    #[serde(rename = "type")]
+    type_: Option<&'a str>,
+    staff: Option<&'a str>,
+    room: Option<&'a str>,
+    campus: Option<&'a str>,
+    groups: String,
+}
+
+impl<'a> From<&'a Event> for Row<'a> {
+    fn from(event: &'a Event) -> Self {
+        let inner = &event.inner;
+        let start = inner.start.with_timezone(&London);
+        let end = inner.end.with_timezone(&London);
+        Row {
+            id: &event.id,
+            date: start.format("%Y-%m-%d").to_string(),
+            start: start.format("%H:%M").to_string(),
+            end: end.format("%H:%M").to_string(),
+            code: &inner.code,
+            title: inner.title.as_deref(),
+            type_: inner.type_.as_deref(),
+            staff: inner.staff.as_deref(),
+            room: inner.room.as_deref(),
+            campus: inner.campus.as_deref(),
+            groups: format_groups(&inner.groups),
+        }
+    }
+}
+
+/// Render events in a format, stamping calendars with `stamp`.
+pub fn export(
+    events: &[Event],
+    format: Format,
+    options: &ical::Options,
+    stamp: &DateTime<Utc>,
+) -> Result<String, Error> {
+    let rows = events.iter().map(Row::from);
+    match format {
+        Format::Ics => Ok(ical::render_calendar(
+            events,
+            &Sequences::new(),
+            options,
+            stamp,
+        )),
+        Format::Json => Ok(serde_json::to_string_pretty(&rows.collect::<Vec<_>>())?),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            let bytes = writer
+                .into_inner()
+                .map_err(|error| Error::Io(error.into_error()))?;
+            Ok(String::from_utf8(bytes).expect("CSV is written from strings"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::TimeZone;
+
+    use adonais_core::keats;
+
+    use super::*;
+
+    fn events() -> Vec<Event> {
+        vec![Event::try_from(keats::Event {
+            date: "2019-09-09T00:00:00".to_owned(),
+            start_time: "09:00".to_owned(),
+            end_time: "12:30".to_owned(),
+            code: "5MBBS201-OW".to_owned(),
+            groups: Some("201-289 except 250".to_owned()),
+            title: Some("Year 2, everything you need to know".to_owned()),
+            type_: Some("Lecture".to_owned()),
+            staff: None,
+            room: Some("Guy's Greenwood Theatre".to_owned()),
+            campus: Some("Guy's".to_owned()),
+        })
+        .unwrap()]
+    }
+
+    fn stamp() -> DateTime<Utc> {
+        Utc.ymd(2019, 9, 1).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("ics".parse::<Format>().unwrap(), Format::Ics);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let events = events();
+        let csv = export(&events, Format::Csv, &Default::default(), &stamp()).unwrap();
+        assert_eq!(
+            csv,
+            format!(
+                "id,date,start,end,code,title,type,staff,room,campus,groups\n\
+                 {},2019-09-09,09:00,12:30,5MBBS201-OW,\"Year 2, everything you need to know\",Lecture,,Guy's Greenwood Theatre,Guy's,\"201-249, 251-289\"\n",
+                events[0].id
+            )
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let events = events();
+        let json = export(&events, Format::Json, &Default::default(), &stamp()).unwrap();
+        let rows: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows[0]["start"], "09:00");
+        assert_eq!(rows[0]["staff"], serde_json::Value::Null);
+        assert_eq!(rows[0]["type"], "Lecture");
+    }
+
+    #[test]
+    fn test_export_ics() {
+        let ics = export(&events(), Format::Ics, &Default::default(), &stamp()).unwrap();
+        // Times are local, in summer time
+        assert!(ics.contains("DTSTART;TZID=Europe/London:20190909T090000\r\n"));
+    }
+}
@@ -0,0 +1,289 @@
+use chrono::{DateTime, FixedOffset};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::Url;
+
+use adonais_core::{google, CalendarUpdateResponse};
+
+use crate::error::{check_status, Error};
+
+/// The Google Calendar API.
+pub const API_URL: &str = "https://www.googleapis.com/calendar/v3";
+
+/// The most events listed per page, which is the most Google allows.
+pub const MAX_RESULTS: u32 = 2500;
+
+/// A page of the [Events List API](https://developers.google.com/calendar/v3/reference/events/list).
+#[derive(Deserialize)]
+struct EventsPage {
+    #[serde(default)]
+    items: Vec<google::ExistingEvent>,
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+}
+
+/// Counts of the changes made to a calendar.
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+/// A calendar, accessed with an OAuth access token.
+pub struct Calendar {
+    client: Client,
+    events_url: Url,
+    token: String,
+}
+
+impl Calendar {
+    pub fn new(api_url: &str, calendar_id: &str, token: &str) -> Result<Self, Error> {
+        let mut events_url = Url::parse(api_url)
+            .map_err(|error| Error::Usage(format!("Invalid API URL '{}': {}", api_url, error)))?;
+        events_url
+            .path_segments_mut()
+            .map_err(|_| Error::Usage(format!("Invalid API URL '{}'", api_url)))?
+            .pop_if_empty()
+            .extend(&["calendars", calendar_id, "events"]);
+        Ok(Calendar {
+            client: Client::new(),
+            events_url,
+            token: token.to_owned(),
+        })
+    }
+
+    fn event_url(&self, id: &str) -> Url {
+        let mut url = self.events_url.clone();
+        url.path_segments_mut()
+            .expect("checked in Calendar::new")
+            .push(id);
+        url
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<reqwest::blocking::Response, Error> {
+        check_status(request.bearer_auth(&self.token).send()?)
+    }
+
+    /// List events in the window, and whether every page was listed.
+    ///
+    /// Listing stops at the first failed page, as the update can still be calculated safely from an incomplete list.
+    /// Nothing is known if the first page fails though, and a token that is refused will not work for any update,
+    /// so those are errors.
+    pub fn list(
+        &self,
+        time_min: &DateTime<FixedOffset>,
+        time_max: Option<&DateTime<FixedOffset>>,
+    ) -> Result<(Vec<google::ExistingEvent>, bool), Error> {
+        let mut events = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("maxResults", MAX_RESULTS.to_string()),
+                ("timeMin", time_min.to_rfc3339()),
+            ];
+            query.extend(time_max.map(|time_max| ("timeMax", time_max.to_rfc3339())));
+            query.extend(
+                page_token
+                    .clone()
+                    .map(|page_token| ("pageToken", page_token)),
+            );
+            let page = self
+                .send(self.client.get(self.events_url.clone()).query(&query))
+                .and_then(|response| Ok(response.json::<EventsPage>()?));
+            match page {
+                Ok(page) => {
+                    events.extend(page.items);
+                    page_token = page.next_page_token;
+                    if page_token.is_none() {
+                        return Ok((events, true));
+                    }
+                }
+                Err(
+                    error @ Error::Status {
+                        status: 401 | 403, ..
+                    },
+                ) => return Err(error),
+                Err(error) if page_token.is_none() => return Err(error),
+                Err(error) => {
+                    eprintln!("Failed to list all events from calendar: {}", error);
+                    return Ok((events, false));
+                }
+            }
+        }
+    }
+
+    /// Delete, create and patch events, stopping at the first failure.
+    pub fn apply(&self, update: &CalendarUpdateResponse) -> Result<Applied, Error> {
+        let mut applied = Applied::default();
+        for id in update.deleted.iter() {
+            self.send(self.client.delete(self.event_url(id)))?;
+            applied.deleted += 1;
+        }
+        for event in update.created.iter() {
+            self.send(self.client.post(self.events_url.clone()).json(event))?;
+            applied.created += 1;
+        }
+        for event in update.updated.iter() {
+            self.send(self.client.patch(self.event_url(&event.id)).json(event))?;
+            applied.updated += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+
+    /// A stand-in for the Calendar API, sending back the method, URL and body of each request.
+    ///
+    /// Events are listed in `pages`, then listing fails with `status`.
+    fn stand_in_failing(
+        pages: Vec<&'static str>,
+        status: u16,
+    ) -> (String, mpsc::Receiver<(String, String, String)>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/calendar/v3", server.server_addr());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut pages = pages.into_iter();
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                assert!(request
+                    .headers()
+                    .iter()
+                    .any(|header| header.field.equiv("Authorization")
+                        && header.value.as_str() == "Bearer token"));
+                let response = match request.method().as_str() {
+                    "GET" => match pages.next() {
+                        Some(page) => Response::from_string(page),
+                        None => Response::from_string("Failed").with_status_code(status),
+                    },
+                    _ => Response::from_string("{}"),
+                };
+                sender
+                    .send((request.method().to_string(), request.url().to_owned(), body))
+                    .unwrap();
+                request.respond(response).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn stand_in(pages: Vec<&'static str>) -> (String, mpsc::Receiver<(String, String, String)>) {
+        stand_in_failing(pages, 503)
+    }
+
+    fn time_min() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2019-09-02T00:00:00+00:00").unwrap()
+    }
+
+    #[test]
+    fn test_list_pages() {
+        let (url, requests) = stand_in(vec![
+            r#"{"items": [{"id": "a"}], "nextPageToken": "page2"}"#,
+            r#"{"items": [{"id": "b"}]}"#,
+        ]);
+        let calendar = Calendar::new(&url, "abc@group.calendar.google.com", "token").unwrap();
+        let (events, complete) = calendar.list(&time_min(), None).unwrap();
+        assert!(complete);
+        assert_eq!(
+            events.iter().map(|e| e.id.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+
+        let (_, first, _) = requests.recv().unwrap();
+        assert!(first.starts_with(
+            "/calendar/v3/calendars/abc@group.calendar.google.com/events?maxResults=2500&timeMin=2019-09-02T00%3A00%3A00%2B00%3A00"
+        ));
+        let (_, second, _) = requests.recv().unwrap();
+        assert!(second.ends_with("&pageToken=page2"));
+    }
+
+    #[test]
+    fn test_list_incomplete() {
+        let (url, _requests) = stand_in(vec![
+            r#"{"items": [{"id": "a"}], "nextPageToken": "page2"}"#,
+        ]);
+        let calendar = Calendar::new(&url, "primary", "token").unwrap();
+        let (events, complete) = calendar.list(&time_min(), None).unwrap();
+        assert!(!complete);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_list_errors() {
+        // Nothing was listed
+        let (url, _requests) = stand_in(vec![]);
+        let calendar = Calendar::new(&url, "primary", "token").unwrap();
+        match calendar.list(&time_min(), None) {
+            Err(Error::Status { status: 503, .. }) => {}
+            other => panic!("Expected a failed listing, got {:?}", other.map(|_| ())),
+        }
+
+        // The token was refused, so nothing else would work either
+        for &status in &[401, 403] {
+            let (url, _requests) = stand_in_failing(
+                vec![r#"{"items": [{"id": "a"}], "nextPageToken": "page2"}"#],
+                status,
+            );
+            let calendar = Calendar::new(&url, "primary", "token").unwrap();
+            match calendar.list(&time_min(), None) {
+                Err(Error::Status { status: failed, .. }) => assert_eq!(failed, status),
+                other => panic!("Expected a failed listing, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let (url, requests) = stand_in(vec![]);
+        let calendar = Calendar::new(&url, "primary", "token").unwrap();
+        let event = google::Event {
+            id: "new1".to_owned(),
+            summary: "Lecture".to_owned(),
+            ..Default::default()
+        };
+        let update = CalendarUpdateResponse {
+            created: vec![event.clone()],
+            updated: vec![google::Event {
+                id: "old1".to_owned(),
+                ..event
+            }],
+            deleted: vec!["gone1".to_owned()],
+            edited: vec![],
+            diagnostics: vec![],
+            safe_mode: false,
+        };
+        assert_eq!(
+            calendar.apply(&update).unwrap(),
+            Applied {
+                created: 1,
+                updated: 1,
+                deleted: 1
+            }
+        );
+
+        let requests: Vec<_> = requests.try_iter().collect();
+        assert_eq!(
+            requests
+                .iter()
+                .map(|(method, url, _)| (method.as_str(), url.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("DELETE", "/calendar/v3/calendars/primary/events/gone1"),
+                ("POST", "/calendar/v3/calendars/primary/events"),
+                ("PATCH", "/calendar/v3/calendars/primary/events/old1"),
+            ]
+        );
+        let created: serde_json::Value = serde_json::from_str(&requests[1].2).unwrap();
+        assert_eq!(created["id"], "new1");
+        assert_eq!(created["summary"], "Lecture");
+    }
+}
@@ -1,14 +1,714 @@
-extern crate reqwest;
+//! Run the sync pipeline from the command line, to debug it without the browser UI.
 
-extern crate adonais_core;
+#[macro_use]
+extern crate serde_derive;
 
-use reqwest::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use adonais_core::keats::{Event, URI};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Europe::London;
+use structopt::StructOpt;
 
-fn main() -> Result<(), Error> {
-    let mut response = reqwest::get(URI)?;
-    let events: Vec<Event> = response.json()?;
-    println!("{:?}", events);
-    Ok(())
+use adonais_core::keats::groups_parser::{Group, GroupUniverse};
+use adonais_core::keats::{self, client};
+use adonais_core::{
+    changes, filter, google, ical, style, template, CalendarUpdateRequest, Event, GroupAssignment,
+};
+
+mod error;
+mod export;
+mod google_api;
+mod source;
+
+use error::Error;
+
+/// Which events to sync, and where to get them from.
+#[derive(Debug, StructOpt)]
+struct Selection {
+    #[structopt(flatten)]
+    source: source::Source,
+    /// A group to include events for. May be given more than once.
+    #[structopt(long = "group", required = true, number_of_values = 1)]
+    groups: Vec<String>,
+    /// Start the window on this date, rather than a week ago.
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+    /// End the window before this date.
+    #[structopt(long)]
+    until: Option<NaiveDate>,
+    /// End the window this many weeks after it starts, if before `--until`.
+    #[structopt(long, default_value = "12")]
+    weeks: u32,
+    /// A filter as JSON, e.g. `{"not": {"type": "Self-directed learning"}}`. By default, events for the groups.
+    #[structopt(long)]
+    filter: Option<String>,
+    /// Style rules as JSON, e.g. `[{"type": "Exam*", "color_id": "11", "reminders": [60]}]`.
+    #[structopt(long)]
+    styles: Option<String>,
+    /// Templates as JSON, e.g. `{"summary": "[{type}] {title|code}"}`. Fields left out keep their defaults.
+    #[structopt(long)]
+    templates: Option<String>,
+    /// The groups of the cohort, who get events with no groups given.
+    #[structopt(long, default_value = "200-299")]
+    universe: GroupUniverse,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Sync KEATS timetables to calendars")]
+enum Command {
+    /// Save KEATS events as JSON.
+    Fetch {
+        #[structopt(flatten)]
+        source: source::Source,
+        /// Where to save the events. By default, they are printed.
+        #[structopt(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Print the update to a calendar holding a saved list of existing events.
+    Diff {
+        #[structopt(flatten)]
+        selection: Selection,
+        /// Existing Google events as JSON: a list of ids like `["abc123"]`, a list of events,
+        /// or a response from the Events List API.
+        #[structopt(long)]
+        existing: PathBuf,
+        /// Treat the existing events as incomplete, so only updates are calculated.
+        #[structopt(long)]
+        incomplete: bool,
+        /// The calendar only holds events adonais generated, so events from adonais 0.1.0 may be deleted.
+        #[structopt(long)]
+        dedicated: bool,
+    },
+    /// Export the selected events.
+    Export {
+        #[structopt(flatten)]
+        selection: Selection,
+        /// One of `ics`, `json` or `csv`.
+        #[structopt(long, default_value = "ics")]
+        format: export::Format,
+        /// Shown as the calendar name in `ics` exports.
+        #[structopt(long)]
+        name: Option<String>,
+        /// Where to save the export. By default, it is printed.
+        #[structopt(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Print how the selected sessions have changed since a timetable saved by `fetch`.
+    Changes {
+        #[structopt(flatten)]
+        selection: Selection,
+        /// The earlier timetable, as saved by `fetch`.
+        #[structopt(long)]
+        old: PathBuf,
+        /// Write the changes as an HTML list, rather than text.
+        #[structopt(long)]
+        html: bool,
+        /// Where to save the changes. By default, they are printed.
+        #[structopt(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Update a Google calendar.
+    Push {
+        #[structopt(flatten)]
+        selection: Selection,
+        /// The id of the calendar to update.
+        #[structopt(long)]
+        calendar: String,
+        /// An OAuth access token with the `calendar.events` scope.
+        #[structopt(long, env = "ADONAIS_GOOGLE_TOKEN", hide_env_values = true)]
+        token: String,
+        /// The Google Calendar API, which may be replaced by a stand-in for testing.
+        #[structopt(long, default_value = google_api::API_URL)]
+        api: String,
+        /// The calendar only holds events adonais generated, so events from adonais 0.1.0 may be deleted.
+        #[structopt(long)]
+        dedicated: bool,
+        /// Print the update rather than applying it.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Push even if the cached timetable and selection are unchanged since the last push,
+        /// e.g. to put back events deleted by hand.
+        #[structopt(long)]
+        force: bool,
+    },
+}
+
+/// Midnight at the start of a date in London.
+fn london_midnight(date: NaiveDate) -> DateTime<FixedOffset> {
+    let midnight = London
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        .expect("London has no gaps at midnight");
+    midnight.with_timezone(&midnight.offset().fix())
+}
+
+/// Parse an optional JSON argument, which is the default if not given.
+fn parse_json_option<T>(name: &str, json: &Option<String>) -> Result<T, Error>
+where
+    T: Default + serde::de::DeserializeOwned,
+{
+    match json {
+        Some(json) => serde_json::from_str(json)
+            .map_err(|error| Error::Usage(format!("Invalid {}: {}", name, error))),
+        None => Ok(Default::default()),
+    }
+}
+
+/// Existing events may be saved as a list of ids, a list of events, or the response they were listed in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExistingEvents {
+    Ids(Vec<String>),
+    List(Vec<google::ExistingEvent>),
+    Response { items: Vec<google::ExistingEvent> },
+}
+
+impl ExistingEvents {
+    /// The events, with only ids known for a list of ids, so none of them count as generated.
+    fn into_events(self) -> Vec<google::ExistingEvent> {
+        match self {
+            ExistingEvents::Ids(ids) => ids
+                .into_iter()
+                .map(|id| google::ExistingEvent {
+                    id,
+                    ..Default::default()
+                })
+                .collect(),
+            ExistingEvents::List(events) => events,
+            ExistingEvents::Response { items } => items,
+        }
+    }
+}
+
+impl Selection {
+    fn time_min(&self, today: NaiveDate) -> DateTime<FixedOffset> {
+        london_midnight(self.from.unwrap_or(today - Duration::weeks(1)))
+    }
+
+    fn time_max(&self) -> Option<DateTime<FixedOffset>> {
+        self.until.map(london_midnight)
+    }
+
+    fn assignments(&self) -> Vec<GroupAssignment> {
+        self.groups
+            .iter()
+            .map(|group| GroupAssignment::from(Group::from(group.as_str())))
+            .collect()
+    }
+
+    fn filter(&self) -> Result<filter::Filter, Error> {
+        parse_json_option("filter", &self.filter)
+    }
+
+    fn styles(&self) -> Result<Vec<style::StyleRule>, Error> {
+        parse_json_option("styles", &self.styles)
+    }
+
+    fn templates(&self) -> Result<template::Templates, Error> {
+        parse_json_option("templates", &self.templates)
+    }
+
+    /// How exported events are written.
+    fn options(&self, name: Option<String>) -> Result<ical::Options, Error> {
+        Ok(ical::Options {
+            name,
+            styles: self.styles()?,
+            templates: self.templates()?,
+        })
+    }
+
+    /// Which changes between timetables to report.
+    fn change_filter(&self, today: NaiveDate) -> Result<changes::ChangeFilter, Error> {
+        let time_min = self.time_min(today);
+        Ok(changes::ChangeFilter {
+            groups: self.assignments(),
+            universe: self.universe.clone(),
+            filter: self.filter()?,
+            time_max: adonais_core::window_end(&time_min, self.time_max(), Some(self.weeks)),
+            time_min: Some(time_min),
+        })
+    }
+
+    /// A request to update a calendar holding `existing` events with `new` ones.
+    fn request(
+        &self,
+        new: Vec<keats::Event>,
+        existing: Vec<google::ExistingEvent>,
+        existing_complete: bool,
+        today: NaiveDate,
+    ) -> Result<CalendarUpdateRequest, Error> {
+        Ok(CalendarUpdateRequest {
+            existing,
+            new,
+            groups: self.assignments(),
+            time_min: self.time_min(today),
+            time_max: self.time_max(),
+            weeks: Some(self.weeks),
+            universe: self.universe.clone(),
+            filter: self.filter()?,
+            styles: self.styles()?,
+            templates: self.templates()?,
+            existing_complete,
+            dedicated: false,
+        })
+    }
+
+    /// Everything a push depends on besides the calendar, for a cached timetable with `hash`.
+    ///
+    /// Only the selection as given counts, not the window derived from today's date,
+    /// so an unchanged timetable is not pushed again just because a day has passed.
+    fn push_key(&self, hash: &str) -> String {
+        serde_json::json!({
+            "timetable": hash,
+            "groups": self.groups,
+            "filter": self.filter,
+            "styles": self.styles,
+            "templates": self.templates,
+            "universe": [self.universe.start, self.universe.end],
+            "from": self.from,
+            "until": self.until,
+            "weeks": self.weeks,
+        })
+        .to_string()
+    }
+
+    /// The selected events, with any problems reported.
+    fn events(&self, today: NaiveDate) -> Result<Vec<Event>, Error> {
+        let (new, _) = self.source.load()?;
+        let request = self.request(new, vec![], true, today)?;
+        let time_max = request.window_end();
+        let (events, diagnostics) = adonais_core::select_events(
+            request.new,
+            &request.groups,
+            &request.filter,
+            &request.universe,
+            &request.time_min,
+            time_max.as_ref(),
+        );
+        for diagnostic in diagnostics {
+            eprintln!(
+                "KEATS event {} on {}: {} ({:?})",
+                diagnostic.event.code, diagnostic.event.date, diagnostic.reason, diagnostic.action
+            );
+        }
+        Ok(events)
+    }
+}
+
+/// What was last pushed to each calendar, kept in the cache so unchanged timetables are not pushed again.
+fn pushed_path(cache: &Path) -> PathBuf {
+    cache.join("pushed.json")
+}
+
+fn read_pushed(cache: &Path) -> Result<HashMap<String, String>, Error> {
+    match fs::read_to_string(pushed_path(cache)) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn write_output(output: &Option<PathBuf>, contents: &str) -> Result<(), Error> {
+    match output {
+        Some(path) => Ok(fs::write(path, contents)?),
+        None => {
+            println!("{}", contents);
+            Ok(())
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), Error> {
+    let today = London
+        .from_utc_datetime(&Utc::now().naive_utc())
+        .date()
+        .naive_local();
+    match command {
+        Command::Fetch { source, output } => {
+            let json = source.read()?;
+            // Only save valid events
+            let events = client::decode(&json)?;
+            eprintln!("Fetched {} events", events.len());
+            write_output(&output, &json)
+        }
+        Command::Diff {
+            selection,
+            existing,
+            incomplete,
+            dedicated,
+        } => {
+            let existing: ExistingEvents = serde_json::from_str(&fs::read_to_string(existing)?)?;
+            let existing = existing.into_events();
+            let (new, _) = selection.source.load()?;
+            let request = CalendarUpdateRequest {
+                dedicated,
+                ..selection.request(new, existing, !incomplete, today)?
+            };
+            let update = adonais_core::calculate_calendar_update(request)?;
+            write_output(&None, &serde_json::to_string_pretty(&update)?)
+        }
+        Command::Export {
+            selection,
+            format,
+            name,
+            output,
+        } => {
+            let events = selection.events(today)?;
+            let options = selection.options(name)?;
+            let exported = export::export(&events, format, &options, &Utc::now())?;
+            write_output(&output, &exported)
+        }
+        Command::Changes {
+            selection,
+            old,
+            html,
+            output,
+        } => {
+            let old = client::decode(&fs::read_to_string(old)?)?;
+            let (new, _) = selection.source.load()?;
+            let changelog = changes::diff_snapshots(&old, &new, &selection.change_filter(today)?);
+            let rendered = if html {
+                changes::render_html(&changelog)
+            } else {
+                changes::render_text(&changelog)
+            };
+            write_output(&output, rendered.trim_end())
+        }
+        Command::Push {
+            selection,
+            calendar: calendar_id,
+            token,
+            api,
+            dedicated,
+            dry_run,
+            force,
+        } => {
+            let (new, hash) = selection.source.load()?;
+            let pushed = match (selection.source.cache(), hash) {
+                (Some(cache), Some(hash)) => {
+                    Some((cache, read_pushed(cache)?, selection.push_key(&hash)))
+                }
+                _ => None,
+            };
+            if let Some((_, pushed, key)) = &pushed {
+                if !force && pushed.get(&calendar_id) == Some(key) {
+                    eprintln!("Nothing to push, as the timetable and selection are unchanged since the last push");
+                    return Ok(());
+                }
+            }
+
+            let calendar = google_api::Calendar::new(&api, &calendar_id, &token)?;
+            let time_min = selection.time_min(today);
+            let time_max =
+                adonais_core::window_end(&time_min, selection.time_max(), Some(selection.weeks));
+            let (existing, complete) = calendar.list(&time_min, time_max.as_ref())?;
+            eprintln!("Got {} events from calendar", existing.len());
+            let request = CalendarUpdateRequest {
+                dedicated,
+                ..selection.request(new, existing, complete, today)?
+            };
+            let update = adonais_core::calculate_calendar_update(request)?;
+            if update.safe_mode {
+                eprintln!("Calendar listing was incomplete, so only updating known events");
+            }
+            if dry_run {
+                return write_output(&None, &serde_json::to_string_pretty(&update)?);
+            }
+            let applied = calendar.apply(&update)?;
+            eprintln!(
+                "Created {}, updated {} and deleted {} events",
+                applied.created, applied.updated, applied.deleted
+            );
+
+            // Only a complete push can be skipped next time
+            if let (Some((cache, mut pushed, key)), false) = (pushed, update.safe_mode) {
+                pushed.insert(calendar_id, key);
+                fs::write(pushed_path(cache), serde_json::to_string(&pushed)?)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    if let Err(error) = run(Command::from_args()) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(args: &[&str]) -> Selection {
+        let args = ["adonais_sync", "export"].iter().chain(args);
+        match Command::from_iter_safe(args).unwrap() {
+            Command::Export { selection, .. } => selection,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let selection = selection(&["--group", "253", "--group", "A1", "--until", "2019-12-20"]);
+        assert_eq!(selection.groups, vec!["253", "A1"]);
+        assert_eq!(selection.weeks, 12);
+
+        assert!(Command::from_iter_safe(&["adonais_sync", "export"]).is_err());
+        assert!(Command::from_iter_safe(&[
+            "adonais_sync",
+            "export",
+            "--group",
+            "1",
+            "--format",
+            "xml"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_push_key() {
+        let default = selection(&["--group", "253"]);
+        assert_eq!(
+            default.push_key("abc"),
+            selection(&["--group", "253"]).push_key("abc")
+        );
+        assert_ne!(default.push_key("abc"), default.push_key("def"));
+        let from = selection(&["--group", "253", "--from", "2019-09-02"]);
+        assert_ne!(from.push_key("abc"), default.push_key("abc"));
+    }
+
+    #[test]
+    fn test_window() {
+        let today = NaiveDate::from_ymd(2019, 9, 9);
+        let default = selection(&["--group", "253"]);
+        assert_eq!(
+            default.time_min(today).to_rfc3339(),
+            "2019-09-02T00:00:00+01:00"
+        );
+        assert_eq!(default.time_max(), None);
+
+        let winter = selection(&[
+            "--group",
+            "253",
+            "--from",
+            "2019-11-01",
+            "--until",
+            "2019-12-01",
+        ]);
+        assert_eq!(
+            winter.time_min(today).to_rfc3339(),
+            "2019-11-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            winter.time_max().unwrap().to_rfc3339(),
+            "2019-12-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_existing_events_formats() {
+        for json in &[
+            r#"["a"]"#,
+            r#"[{"id": "a"}]"#,
+            r#"{"kind": "calendar#events", "items": [{"id": "a"}]}"#,
+        ] {
+            let existing: ExistingEvents = serde_json::from_str(json).unwrap();
+            let events = existing.into_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].id, "a");
+            assert!(!events[0].is_generated());
+        }
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        let selection = selection(&["--group", "253", "--filter", "{\"spam\": 1}"]);
+        match selection.request(vec![], vec![], true, NaiveDate::from_ymd(2019, 9, 9)) {
+            Err(Error::Usage(message)) => assert!(message.starts_with("Invalid filter")),
+            other => panic!("Expected a usage error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_request_options() {
+        let today = NaiveDate::from_ymd(2019, 9, 9);
+        let request = selection(&["--group", "253"])
+            .request(vec![], vec![], true, today)
+            .unwrap();
+        assert_eq!(request.universe, GroupUniverse::default());
+        assert_eq!(request.styles, vec![]);
+        assert_eq!(request.templates, Default::default());
+
+        let configured = selection(&[
+            "--group",
+            "1",
+            "--universe",
+            "1-40",
+            "--styles",
+            r#"[{"type": "Exam*", "color_id": "11"}]"#,
+            "--templates",
+            r#"{"summary": "[{type}] {title|code}"}"#,
+        ]);
+        let request = configured.request(vec![], vec![], true, today).unwrap();
+        assert_eq!(request.universe, GroupUniverse { start: 1, end: 40 });
+        assert_eq!(request.styles.len(), 1);
+        assert_eq!(
+            request.templates.summary,
+            "[{type}] {title|code}".parse().unwrap()
+        );
+        assert_eq!(
+            request.templates.location,
+            template::Templates::default().location
+        );
+        assert_eq!(
+            configured.change_filter(today).unwrap().universe,
+            request.universe
+        );
+        let options = configured.options(None).unwrap();
+        assert_eq!(options.styles, request.styles);
+        assert_eq!(options.templates, request.templates);
+
+        for (flag, value) in &[
+            ("--styles", "{}"),
+            ("--templates", r#"{"summary": "{spam}"}"#),
+        ] {
+            let selection = selection(&["--group", "1", flag, value]);
+            match selection.request(vec![], vec![], true, today) {
+                Err(Error::Usage(message)) => assert!(message.starts_with("Invalid")),
+                other => panic!("Expected a usage error, got {:?}", other.map(|_| ())),
+            }
+        }
+        assert!(Command::from_iter_safe(&[
+            "adonais_sync",
+            "export",
+            "--group",
+            "1",
+            "--universe",
+            "40-1"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_push_skips_unchanged() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        use tiny_http::{Response, Server};
+
+        // Stand-ins for both KEATS and the Calendar API, sending back each request method and path
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match (request.method().as_str(), request.url()) {
+                    ("GET", "/keats/Mod-Module.5MBBSStage2") => Response::from_string(
+                        r#"[{"C": "5MBBS201-OW", "Date": "2019-09-09T00:00:00", "N": null, "T": null,
+                             "ST": "09:00", "ET": "12:30", "G": "253", "S": null, "R": null, "CP": null}]"#,
+                    ),
+                    ("GET", _) => Response::from_string(r#"{"items": []}"#),
+                    _ => Response::from_string("{}"),
+                };
+                let path = request.url().split('?').next().unwrap().to_owned();
+                sender
+                    .send(format!("{} {}", request.method(), path))
+                    .unwrap();
+                request.respond(response).unwrap();
+            }
+        });
+
+        let cache = std::env::temp_dir().join(format!("adonais_sync_push_{}", std::process::id()));
+        let push = |extra: &[&str]| {
+            let keats_url = format!("{}/keats", url);
+            let api = format!("{}/calendar/v3", url);
+            let mut args = vec![
+                "adonais_sync",
+                "push",
+                "--group",
+                "253",
+                "--from",
+                "2019-09-02",
+                "--calendar",
+                "primary",
+                "--token",
+                "token",
+                "--keats-url",
+                &keats_url,
+                "--api",
+                &api,
+                "--cache",
+                cache.to_str().unwrap(),
+            ];
+            args.extend(extra);
+            run(Command::from_iter_safe(args).unwrap()).unwrap();
+            requests.try_iter().collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            push(&[]),
+            vec![
+                "GET /keats/Mod-Module.5MBBSStage2",
+                "GET /calendar/v3/calendars/primary/events",
+                "POST /calendar/v3/calendars/primary/events",
+            ]
+        );
+        // Only KEATS is asked again
+        assert_eq!(push(&[]), vec!["GET /keats/Mod-Module.5MBBSStage2"]);
+        // Unless the selection changes, or the push is forced
+        assert_eq!(push(&["--weeks", "4"]).len(), 3);
+        assert_eq!(push(&["--weeks", "4", "--force"]).len(), 3);
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_changes() {
+        let directory =
+            std::env::temp_dir().join(format!("adonais_sync_changes_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let timetable = |room: &str| {
+            format!(
+                r#"[{{"C": "5MBBS201", "Date": "2019-09-09T00:00:00", "N": "Anatomy practical", "T": null,
+                     "ST": "09:00", "ET": "12:30", "G": "253", "S": null, "R": "{}", "CP": null}}]"#,
+                room
+            )
+        };
+        let (old, new, output) = (
+            directory.join("old.json"),
+            directory.join("new.json"),
+            directory.join("changes.html"),
+        );
+        fs::write(&old, timetable("Dissection Room")).unwrap();
+        fs::write(&new, timetable("Dissection Room 2")).unwrap();
+
+        let command = Command::from_iter_safe(&[
+            "adonais_sync",
+            "changes",
+            "--old",
+            old.to_str().unwrap(),
+            "--source",
+            new.to_str().unwrap(),
+            "--group",
+            "253",
+            "--from",
+            "2019-09-02",
+            "--html",
+            "--output",
+            output.to_str().unwrap(),
+        ])
+        .unwrap();
+        run(command).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "<ul class=\"changes\">\n  \
+             <li class=\"relocated\"><strong>Relocated</strong> Mon 9 Sep 09:00-12:30 Anatomy practical (5MBBS201) for 253 from Dissection Room to Dissection Room 2</li>\n\
+             </ul>"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use structopt::StructOpt;
+
+use adonais_core::keats::snapshot::{self, FileStore, Freshness};
+use adonais_core::keats::{self, client};
+
+use crate::error::{check_status, Error};
+
+/// Where to get KEATS events from.
+#[derive(Debug, StructOpt)]
+pub struct Source {
+    /// KEATS events from a URL, or a file saved by `fetch`, rather than from the module.
+    #[structopt(long)]
+    source: Option<String>,
+    /// The KEATS module to fetch, e.g. `5MBBSStage3`.
+    #[structopt(long, default_value = "5MBBSStage2")]
+    module: client::ModuleId,
+    /// The KEATS API, which may be replaced by a stand-in for testing.
+    #[structopt(long, default_value = client::BASE_URL)]
+    keats_url: String,
+    /// Keep the last good timetable for the module in this directory,
+    /// to spot when it is unchanged and to fall back on when KEATS is down.
+    #[structopt(long, conflicts_with = "source")]
+    cache: Option<PathBuf>,
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+impl Source {
+    pub fn cache(&self) -> Option<&Path> {
+        self.cache.as_deref()
+    }
+
+    fn snapshot(&self, cache: &Path) -> Result<snapshot::Fetched, Error> {
+        let client = client::Client::new(&self.keats_url);
+        let store = FileStore::new(cache.to_owned());
+        let fetched = client.fetch_snapshot(&self.module, &store, Utc::now())?;
+        match &fetched.freshness {
+            Freshness::Changed => {}
+            Freshness::Unchanged => eprintln!("KEATS timetable is unchanged"),
+            Freshness::Stale(error) => eprintln!(
+                "Using the KEATS timetable from {}, as KEATS could not be used: {}",
+                fetched.snapshot.fetched.to_rfc3339(),
+                error
+            ),
+        }
+        Ok(fetched)
+    }
+
+    /// Read KEATS JSON without decoding it.
+    pub fn read(&self) -> Result<String, Error> {
+        match (&self.source, &self.cache) {
+            (Some(url), _) if is_url(url) => {
+                Ok(check_status(reqwest::blocking::get(url)?)?.text()?)
+            }
+            (Some(path), _) => Ok(fs::read_to_string(path)?),
+            (None, Some(cache)) => Ok(self.snapshot(cache)?.snapshot.body),
+            (None, None) => Ok(client::Client::new(&self.keats_url).fetch_json(&self.module)?),
+        }
+    }
+
+    /// Read and decode KEATS events, with the hash of the cached timetable they came from, if any.
+    pub fn load(&self) -> Result<(Vec<keats::Event>, Option<String>), Error> {
+        match (&self.source, &self.cache) {
+            (None, Some(cache)) => {
+                let fetched = self.snapshot(cache)?;
+                Ok((fetched.events, Some(fetched.snapshot.hash)))
+            }
+            _ => Ok((client::decode(&self.read()?)?, None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+
+    const EVENTS: &str = r#"[{
+        "C": "5MBBS201-OW",
+        "Date": "2019-09-09T00:00:00",
+        "N": "Year 2 Everything you need to know",
+        "T": "Lecture",
+        "ST": "09:00",
+        "ET": "12:30",
+        "G": "201-289",
+        "S": null,
+        "R": "Guy's Greenwood Theatre",
+        "CP": "Guy's"
+    }]"#;
+
+    fn source(args: &[&str]) -> Source {
+        Source::from_iter_safe(["source"].iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn test_load_from_module_url_and_file() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/values", server.server_addr());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match request.url() {
+                    "/values/Mod-Module.5MBBSStage2" | "/events" => Response::from_string(EVENTS),
+                    _ => Response::from_string("Not found").with_status_code(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+
+        let (events, hash) = source(&["--keats-url", &base_url]).load().unwrap();
+        assert_eq!(hash, None);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, "5MBBS201-OW");
+
+        match source(&["--keats-url", &base_url, "--module", "5MBBSStage3"]).load() {
+            Err(Error::Keats(client::Error::Status { status: 404, .. })) => {}
+            other => panic!("Expected a 404, got {:?}", other),
+        }
+
+        let url = base_url.replace("/values", "/events");
+        assert_eq!(source(&["--source", &url]).load().unwrap().0, events);
+        match source(&["--source", &format!("{}/missing", url)]).load() {
+            Err(Error::Status { status: 404, .. }) => {}
+            other => panic!("Expected a 404, got {:?}", other),
+        }
+
+        let path = std::env::temp_dir().join(format!("adonais_sync_{}.json", std::process::id()));
+        fs::write(&path, EVENTS).unwrap();
+        assert_eq!(
+            source(&["--source", path.to_str().unwrap()])
+                .load()
+                .unwrap()
+                .0,
+            events
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(Source::from_iter_safe(&["source", "--module", "5MBBS"]).is_err());
+    }
+
+    #[test]
+    fn test_load_cached() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/values", server.server_addr());
+        thread::spawn(move || {
+            // KEATS goes down after the first request
+            for (index, request) in server.incoming_requests().enumerate() {
+                let response = match index {
+                    0 => Response::from_string(EVENTS),
+                    _ => Response::from_string("Down").with_status_code(503),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        let cache = std::env::temp_dir().join(format!("adonais_sync_cache_{}", std::process::id()));
+        let cached = source(&["--keats-url", &base_url, "--cache", cache.to_str().unwrap()]);
+
+        let (events, hash) = cached.load().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(hash.is_some());
+        assert_eq!(cached.load().unwrap(), (events, hash));
+        assert_eq!(
+            client::decode(&cached.read().unwrap()).unwrap()[0].code,
+            "5MBBS201-OW"
+        );
+        fs::remove_dir_all(&cache).unwrap();
+
+        assert!(
+            Source::from_iter_safe(&["source", "--source", "keats.json", "--cache", "cache"])
+                .is_err()
+        );
+    }
+}
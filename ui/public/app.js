@@ -1,6 +1,15 @@
-import init, { calculate_calendar_update_wasm } from "./pkg/adonais_core.js";
+import init, {
+    calculate_calendar_update_wasm,
+    calculate_ical_wasm,
+    keats_url_wasm,
+    revalidate_keats_wasm
+} from "./pkg/adonais_core.js";
 
 const MS_WEEK = 1000 * 60 * 60 * 24 * 7;
+// How far ahead to sync, keeping the calendar listing well under maxResults
+const SYNC_WEEKS = 12;
+// Events listed per page
+const MAX_RESULTS = 2500;
 
 /**
  * Split an array into smaller arrays of length chunkSize.
@@ -84,14 +93,104 @@ var ui = new firebaseui.auth.AuthUI(firebase.auth());
 // Disable auto-sign in.
 ui.disableAutoSignIn();
 
-const URI = "https://europe-west2-adonais-a3bf8.cloudfunctions.net/proxy-keats";
+// Used for any setting not saved under "settings" in localStorage
+const DEFAULT_SETTINGS = {
+    // A proxy for KEATS, adding CORS headers, with modules at /Mod-Module.<module>
+    keatsUrl: "https://europe-west2-adonais-a3bf8.cloudfunctions.net/proxy-keats",
+    // The KEATS timetable, being a programme and stage
+    module: "5MBBSStage2",
+    // The groups of the cohort, who get events with no groups given
+    universe: { start: 200, end: 299 }
+};
+
+/**
+ * Loads the user's settings, falling back on the defaults.
+ */
+function loadSettings() {
+    return Object.assign(
+        {},
+        DEFAULT_SETTINGS,
+        JSON.parse(localStorage.getItem("settings") || "{}")
+    );
+}
+
+/**
+ * Runs a request on the store of KEATS snapshots, resolving with its result once committed.
+ * Snapshots are kept in IndexedDB, as a timetable can be larger than localStorage allows.
+ */
+function snapshotRequest(mode, makeRequest) {
+    return new Promise((resolve, reject) => {
+        const open = indexedDB.open("adonais", 1);
+        open.onupgradeneeded = () =>
+            open.result.createObjectStore("keats_snapshots", { keyPath: "module" });
+        open.onerror = () => reject(open.error);
+        open.onsuccess = () => {
+            const transaction = open.result.transaction("keats_snapshots", mode);
+            const request = makeRequest(transaction.objectStore("keats_snapshots"));
+            transaction.oncomplete = () => resolve(request.result);
+            transaction.onerror = () => reject(transaction.error);
+            transaction.onabort = () => reject(transaction.error);
+        };
+    });
+}
 
 /**
- * Fetches keats events in JSON format from the API
+ * Fetches keats events in JSON format from the API.
+ * The last good response is kept as a snapshot, to spot when the timetable is unchanged,
+ * and to fall back on when KEATS is down.
  */
-async function fetchEvents() {
-    const response = await fetch(URI);
-    return response.json();
+async function fetchEvents(settings) {
+    const url = keats_url_wasm(settings.keatsUrl, settings.module);
+    // Snapshots were kept in localStorage before, where they could outgrow the quota
+    localStorage.removeItem("keats_snapshot:" + settings.module);
+    let previous = null;
+    try {
+        previous =
+            (await snapshotRequest("readonly", store => store.get(settings.module))) ||
+            null;
+    } catch (error) {
+        userLog("Could not load KEATS snapshot: " + error);
+    }
+    let request = {
+        module: settings.module,
+        url: url,
+        previous: previous
+    };
+    try {
+        const response = await fetch(url);
+        request.response = {
+            status: response.status,
+            etag: response.headers.get("ETag"),
+            last_modified: response.headers.get("Last-Modified"),
+            body: await response.text()
+        };
+    } catch (error) {
+        request.error = error.toString();
+    }
+    const keats = revalidate_keats_wasm(request);
+    if (keats.freshness === "stale") {
+        userLog(
+            "Using KEATS events from " +
+                keats.snapshot.fetched +
+                ", as KEATS could not be used: " +
+                keats.error
+        );
+    } else {
+        // Only the metadata comes back, so the body is the one fetched, or the previous one if unchanged
+        const body =
+            keats.freshness === "changed" ? request.response.body : previous.body;
+        try {
+            await snapshotRequest("readwrite", store =>
+                store.put(Object.assign({}, keats.snapshot, { body: body }))
+            );
+        } catch (error) {
+            userLog(
+                "Could not save KEATS snapshot, so it can't be used when KEATS is down: " +
+                    error
+            );
+        }
+    }
+    return keats;
 }
 
 async function insertCalendarAndSaveId(user_document_ref) {
@@ -146,34 +245,113 @@ async function startApp(user) {
         .doc(user.user.uid);
 
     let calendar_id = await getCalendarId(user_document_ref);
-    const keatsEvents = await fetchEvents();
+    const settings = loadSettings();
+    const keats = await fetchEvents(settings);
+    const keatsEvents = keats.events;
     userLog("Got " + keatsEvents.length + " events from KEATS");
 
     let now = new Date();
     let timeMin = new Date(now.getTime() - MS_WEEK);
+    let timeMax = new Date(timeMin.getTime() + SYNC_WEEKS * MS_WEEK);
 
-    let googleEvents = await gapi.client.calendar.events.list({
-        calendarId: calendar_id,
-        maxResults: 2500,
-        timeMin: timeMin.toISOString()
+    // Either bare groups, or { group, from, until } with inclusive dates
+    let groups = [253];
+    let filter = "assigned";
+    // Make exams stand out, with a day's notice
+    let styles = [{ type: "Exam*", color_id: "11", reminders: [1440, 60] }];
+
+    // Syncing the same timetable with the same settings again that day would change nothing
+    let syncKey = JSON.stringify({
+        timetable: keats.snapshot.hash,
+        calendar_id: calendar_id,
+        module: settings.module,
+        universe: settings.universe,
+        groups: groups,
+        filter: filter,
+        styles: styles,
+        day: now.toISOString().slice(0, 10)
     });
-    let existingEventIds = googleEvents.result.items.map(event => event.id);
-    userLog("Got " + existingEventIds.length + " events from calendar");
+    if (localStorage.getItem("last_sync") === syncKey) {
+        userLog(
+            "Nothing to sync, as the timetable is unchanged since the last sync today"
+        );
+        return;
+    }
+
+    // The same window must be used to list existing events and filter new ones
+    let existingEvents = [];
+    let existingComplete = true;
+    let pageToken = undefined;
+    do {
+        let googleEvents;
+        try {
+            googleEvents = await gapi.client.calendar.events.list({
+                calendarId: calendar_id,
+                maxResults: MAX_RESULTS,
+                timeMin: timeMin.toISOString(),
+                timeMax: timeMax.toISOString(),
+                pageToken: pageToken
+            });
+        } catch (error) {
+            userLog("Failed to list all events from calendar: " + error);
+            existingComplete = false;
+            break;
+        }
+        existingEvents = existingEvents.concat(googleEvents.result.items);
+        pageToken = googleEvents.result.nextPageToken;
+    } while (pageToken);
+    userLog("Got " + existingEvents.length + " events from calendar");
 
-    let group = 253;
     let syncRequest = {
         new: keatsEvents,
-        existing: existingEventIds,
-        group: group,
-        time_min: timeMin.toISOString()
+        existing: existingEvents,
+        groups: groups,
+        time_min: timeMin.toISOString(),
+        time_max: timeMax.toISOString(),
+        universe: settings.universe,
+        filter: filter,
+        styles: styles,
+        existing_complete: existingComplete,
+        // Only adonais uses the calendar it creates
+        dedicated: true
     };
     userLog(
-        "Calculating diff for group " +
-            group +
-            " after " +
-            timeMin.toISOString()
+        "Calculating diff for groups " +
+            JSON.stringify(groups) +
+            " from " +
+            timeMin.toISOString() +
+            " to " +
+            timeMax.toISOString()
     );
-    let syncResponse = calculate_calendar_update_wasm(syncRequest);
+    let syncResponse;
+    try {
+        syncResponse = calculate_calendar_update_wasm(syncRequest);
+    } catch (error) {
+        userLog("Failed to calculate diff: " + error);
+        throw error;
+    }
+    if (syncResponse.edited.length > 0) {
+        userLog(
+            syncResponse.edited.length +
+                " events have been edited by hand, and will not be deleted"
+        );
+    }
+    if (syncResponse.safe_mode) {
+        userLog("Calendar listing was incomplete, so only updating known events");
+    }
+    syncResponse.diagnostics.forEach(diagnostic => {
+        userLog(
+            "KEATS event " +
+                diagnostic.event.C +
+                " on " +
+                diagnostic.event.Date +
+                ": " +
+                diagnostic.reason +
+                " (" +
+                diagnostic.action +
+                ")"
+        );
+    });
 
     const batchSize = 50;
     let batches = [];
@@ -181,6 +359,7 @@ async function startApp(user) {
     userLog(
         syncResponse.deleted.length +
             syncResponse.created.length +
+            syncResponse.updated.length +
             " events need update"
     );
     chunk(syncResponse.deleted, batchSize).forEach(deletedChunk => {
@@ -207,12 +386,74 @@ async function startApp(user) {
         });
         batches.push(batch);
     });
+    chunk(syncResponse.updated, batchSize).forEach(updatedChunk => {
+        let batch = gapi.client.newBatch();
+        updatedChunk.forEach(function(event) {
+            batch.add(
+                gapi.client.calendar.events.patch({
+                    calendarId: calendar_id,
+                    eventId: event.id,
+                    resource: event
+                })
+            );
+        });
+        batches.push(batch);
+    });
 
     const batch_result = await Promise.all(batches);
-    userLog("Done");
+    const failed = batch_result.some(batch =>
+        Object.values(batch.result).some(item => item.status >= 300)
+    );
+    // Only a complete sync can be skipped next time
+    if (!failed && !syncResponse.safe_mode) {
+        localStorage.setItem("last_sync", syncKey);
+    }
+    userLog(failed ? "Done, but some events failed to update" : "Done");
     console.log(batch_result);
 }
 
+/**
+ * Downloads the timetable as an .ics file, for calendars other than Google.
+ * Sequence numbers are kept between downloads, so calendar apps pick up changes.
+ */
+async function downloadIcs() {
+    userLogClear();
+    await init();
+    const settings = loadSettings();
+    const keats = await fetchEvents(settings);
+    let timeMin = new Date(new Date().getTime() - MS_WEEK);
+    let icalRequest = {
+        new: keats.events,
+        groups: [253],
+        time_min: timeMin.toISOString(),
+        weeks: SYNC_WEEKS,
+        universe: settings.universe,
+        filter: "assigned",
+        name: "GKT Year 2",
+        sequences: JSON.parse(localStorage.getItem("ical_sequences") || "{}")
+    };
+    let icalResponse;
+    try {
+        icalResponse = calculate_ical_wasm(icalRequest);
+    } catch (error) {
+        userLog("Failed to export calendar: " + error);
+        throw error;
+    }
+    localStorage.setItem(
+        "ical_sequences",
+        JSON.stringify(icalResponse.sequences)
+    );
+
+    let link = document.createElement("a");
+    link.href = URL.createObjectURL(
+        new Blob([icalResponse.ics], { type: "text/calendar" })
+    );
+    link.download = "adonais.ics";
+    link.click();
+    URL.revokeObjectURL(link.href);
+    userLog("Downloaded calendar");
+}
+
 /**
  * Displays the UI for a signed in user.
  * @param {!firebase.User} user
@@ -332,6 +573,9 @@ var deleteAccount = function() {
  * Initializes the app.
  */
 var initApp = function() {
+    document
+        .getElementById("download-ics")
+        .addEventListener("click", downloadIcs);
     document.getElementById("sign-out").addEventListener("click", function() {
         firebase.auth().signOut();
     });